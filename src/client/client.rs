@@ -1,8 +1,20 @@
 use std::{env, io};
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::net::{TcpStream};
 use env_logger::Builder;
-use log::{error, info, LevelFilter};
+use log::{error, info, warn, LevelFilter};
+use serde_json::json;
+
+// frame handshake magic and the only frame version server understands so far
+const FRAME_MAGIC: [u8; 3] = [0xc1, b'R', b'C'];
+const FRAME_VERSION: u8 = 1;
+
+// how binary values from server responses are shown
+#[derive(Clone, Copy)]
+enum ValueDisplay {
+    Utf8,
+    Hex,
+}
 
 fn main() {
     Builder::new()
@@ -10,10 +22,15 @@ fn main() {
         .init();
 
     let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        error!("Usage: {} <ip> <port>", args[0]);
+    let value_display = match args.get(3).map(|display| display.as_str()) {
+        None | Some("utf8") => Some(ValueDisplay::Utf8),
+        Some("hex") => Some(ValueDisplay::Hex),
+        Some(_) => None,
+    };
+    let Some(value_display) = value_display.filter(|_| args.len() == 3 || args.len() == 4) else {
+        error!("Usage: {} <ip> <port> [utf8|hex]", args[0]);
         return;
-    }
+    };
 
     let ip = &args[1];
     let port = &args[2];
@@ -21,16 +38,74 @@ fn main() {
 
     let mut reader = BufReader::new(stream.try_clone().unwrap());
     let mut writer = BufWriter::new(stream.try_clone().unwrap());
+    // agrees with server on frame version, then every message is sent as length-prefixed MessagePack
+    writer.write_all(&FRAME_MAGIC).unwrap();
+    writer.write_all(&[FRAME_VERSION]).unwrap();
+    writer.flush().unwrap();
+    let mut handshake = [0; 4];
+    reader.read_exact(&mut handshake).unwrap();
+    if handshake != [FRAME_MAGIC[0], FRAME_MAGIC[1], FRAME_MAGIC[2], FRAME_VERSION] {
+        error!("Server doesn't support frame version {FRAME_VERSION}");
+        return;
+    }
     loop {
-        info!("Send the command to server in JSON: Put, Get, Exists, Exit");
+        info!("Send the command to server in JSON: Put, Get, PutIfAbsent, PutIfPresent, Cas, Exists, Delete, DeleteMany, Ttl, Expire, ExpireAt, Persist, Incr, Decr, IncrBy, IncrExisting, IncrByFloat, LPush, RPush, LPop, RPop, LRange, LLen, LTrim, HSet, HGet, HDel, HGetAll, HIncrBy, HExists, SAdd, SRem, SMembers, SIsMember, SCard, ZAdd, ZRange, ZRangeByScore, ZRank, ZIncrBy, Stats, LeaveCluster, Exit");
+        info!("Binary values can be sent as {{\"hex\": \"...\"}}");
         let mut request = String::new();
-        io::stdin().read_line(&mut request).unwrap();
+        if io::stdin().read_line(&mut request).unwrap() == 0 {
+            return;
+        }
         // TODO: provide an easier interface to provide commands (not json)
-        writer.write_all(request.as_bytes()).unwrap();
+        let request: serde_json::Value = match serde_json::from_str(&request) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Request is not valid JSON: {e}");
+                continue;
+            }
+        };
+        // requests are sent in MessagePack, so values come back as raw bytes
+        let payload = rmp_serde::to_vec_named(&request).unwrap();
+        writer.write_all(&(payload.len() as u32).to_be_bytes()).unwrap();
+        writer.write_all(&payload).unwrap();
         writer.flush().unwrap();
 
-        let mut response = String::new();
-        reader.read_line(&mut response).unwrap();
-        info!("Server response: {}", response.trim());
+        let mut len = [0; 4];
+        reader.read_exact(&mut len).unwrap();
+        let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+        reader.read_exact(&mut payload).unwrap();
+        let response = rmpv::decode::read_value(&mut payload.as_slice()).unwrap();
+        info!("Server response: {}", to_json(response, value_display));
+    }
+}
+
+fn to_json(value: rmpv::Value, value_display: ValueDisplay) -> serde_json::Value {
+    match value {
+        rmpv::Value::Nil => serde_json::Value::Null,
+        rmpv::Value::Boolean(boolean) => json!(boolean),
+        rmpv::Value::Integer(integer) => match integer.as_i64() {
+            Some(integer) => json!(integer),
+            None => json!(integer.as_u64()),
+        },
+        rmpv::Value::F32(float) => json!(float),
+        rmpv::Value::F64(float) => json!(float),
+        rmpv::Value::String(string) => json!(string.into_str()),
+        rmpv::Value::Binary(bytes) => match value_display {
+            ValueDisplay::Utf8 => json!(String::from_utf8_lossy(&bytes)),
+            ValueDisplay::Hex => json!(hex::encode(bytes)),
+        },
+        rmpv::Value::Array(values) => values.into_iter().map(|value| to_json(value, value_display)).collect(),
+        rmpv::Value::Map(entries) => {
+            let entries = entries.into_iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        rmpv::Value::String(key) => key.into_str().unwrap_or_default(),
+                        key => key.to_string(),
+                    };
+                    (key, to_json(value, value_display))
+                })
+                .collect();
+            serde_json::Value::Object(entries)
+        }
+        rmpv::Value::Ext(_, _) => serde_json::Value::Null,
     }
 }
\ No newline at end of file
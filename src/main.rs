@@ -13,17 +13,42 @@ mod server {
     mod commands;
 
     pub mod cluster;
+
+    pub mod heartbeat;
+
+    pub mod eviction;
+
+    pub mod values;
+
+    mod wire_format;
+
+    mod framing;
+
+    pub mod connection_pool;
+
+    mod resp;
+
+    mod memcached;
+
+    pub mod bench;
+
+    #[cfg(test)]
+    mod test_util;
 }
 
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::str::FromStr;
+use std::time::Duration;
 use clap::Parser;
-use crate::server::cache::Cache;
+use crate::server::cache::{Cache, CacheConfig};
 use log::{info, LevelFilter};
 use env_logger::Builder;
-use crate::server::cluster::{Cluster, NodeId};
-use rand::distributions::{Alphanumeric, DistString};
+use crate::server::cluster::{Cluster, NodeAddrs, NodeId, ReplicationConfig};
+use crate::server::connection_pool::ForwardingConfig;
+use crate::server::eviction::EvictionPolicyKind;
+use crate::server::heartbeat::HeartbeatConfig;
+use rand::distr::{Alphanumeric, SampleString};
 
 
 #[derive(Parser)]
@@ -40,6 +65,57 @@ struct Cli {
 
     #[arg(long)]
     leader: Option<String>,
+
+    // interface listeners are bound to
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    bind_ip: IpAddr,
+
+    // addresses other nodes are told to use, when they differ from the listeners, e.g. behind NAT
+    #[arg(long)]
+    advertised_server_addr: Option<SocketAddr>,
+
+    #[arg(long)]
+    advertised_client_addr: Option<SocketAddr>,
+
+    // memcached text protocol listener is started only if the port is given
+    #[arg(long)]
+    memcached_port: Option<u32>,
+
+    #[arg(long, default_value_t = 1000)]
+    heartbeat_interval_ms: u64,
+
+    #[arg(long, default_value_t = 3000)]
+    suspicion_timeout_ms: u64,
+
+    #[arg(long, default_value_t = 10000)]
+    failure_timeout_ms: u64,
+
+    #[arg(long, default_value_t = 1)]
+    replicas: usize,
+
+    #[arg(long)]
+    read_from_replica: bool,
+
+    #[arg(long, default_value_t = 16)]
+    forward_max_in_flight: usize,
+
+    #[arg(long, default_value_t = 1000)]
+    forward_connect_timeout_ms: u64,
+
+    #[arg(long, default_value_t = 5000)]
+    forward_request_timeout_ms: u64,
+
+    #[arg(long, default_value_t = 16)]
+    cache_shards: usize,
+
+    #[arg(long)]
+    max_entries: Option<usize>,
+
+    #[arg(long)]
+    max_bytes: Option<usize>,
+
+    #[arg(long, value_enum, default_value_t = EvictionPolicyKind::Lru)]
+    eviction_policy: EvictionPolicyKind,
 }
 
 
@@ -49,38 +125,75 @@ fn main() {
         .init();
 
     let cli = Cli::parse();
-    let cache = Cache::new();
     let client_port: u32 = cli.client_port;
     let server_port: u32 = cli.server_port;
+    let memcached_port: Option<u32> = cli.memcached_port;
     let num_buckets = 16;
     let self_id = format!("node-{}", generate_node_id());
     // if ip of node to connect is provided, parse it and try to connect
     let leader_ip = cli.leader.and_then(|l| SocketAddr::from_str(l.as_str()).ok());
+    let bind_ip = cli.bind_ip;
+    let self_addrs = NodeAddrs {
+        server: cli.advertised_server_addr.unwrap_or(SocketAddr::new(bind_ip, server_port as u16)),
+        client: cli.advertised_client_addr.unwrap_or(SocketAddr::new(bind_ip, client_port as u16)),
+    };
+    let heartbeat_config = HeartbeatConfig {
+        interval: Duration::from_millis(cli.heartbeat_interval_ms),
+        suspicion_timeout: Duration::from_millis(cli.suspicion_timeout_ms),
+        failure_timeout: Duration::from_millis(cli.failure_timeout_ms),
+    };
+    let replication_config = ReplicationConfig {
+        replicas: cli.replicas,
+        read_from_replica: cli.read_from_replica,
+    };
+    let forwarding_config = ForwardingConfig {
+        max_in_flight: cli.forward_max_in_flight,
+        connect_timeout: Duration::from_millis(cli.forward_connect_timeout_ms),
+        request_timeout: Duration::from_millis(cli.forward_request_timeout_ms),
+    };
+    let cache_config = CacheConfig {
+        shards: cli.cache_shards,
+        max_entries: cli.max_entries,
+        max_bytes: cli.max_bytes,
+        eviction_policy: cli.eviction_policy,
+    };
     info!("Starting with params:
      - client port: {client_port};
      - server port: {server_port};
+     - memcached port: {memcached_port:?};
      - num buckets: {num_buckets};
      - id: {self_id};
      - leader ip: {leader_ip:?};
+     - bind ip: {bind_ip};
+     - advertised addresses: {self_addrs:?};
+     - heartbeats: {heartbeat_config:?};
+     - replication: {replication_config:?};
+     - forwarding: {forwarding_config:?};
+     - cache: {cache_config:?};
     ");
 
-    let cluster_state = Cluster::new(num_buckets, self_id, leader_ip);
+    let cache = Cache::new(cache_config);
+    let cluster_state = Cluster::new(num_buckets, replication_config, forwarding_config, self_id, self_addrs, leader_ip);
 
     match cli.run_mode.as_str() {
         "server" => {
             info!("Running in server mode.");
-            server::listener::start_server(cache, cluster_state, client_port, server_port);
+            server::listener::start_server(cache, cluster_state, bind_ip, client_port, server_port, memcached_port, heartbeat_config);
         }
         "test" => {
             info!("Running cache testing mode.");
             server::local_test::run_test_mode(cache, cluster_state);
         }
+        "bench" => {
+            info!("Running cache benchmark.");
+            server::bench::run_bench_mode(cache);
+        }
         _ => {
-            panic!("Invalid run mode. Please use 'server' or 'test'.");
+            panic!("Invalid run mode. Please use 'server', 'test' or 'bench'.");
         }
     }
 }
 
 fn generate_node_id() -> NodeId {
-    Alphanumeric.sample_string(&mut rand::thread_rng(), 5)
+    Alphanumeric.sample_string(&mut rand::rng(), 5)
 }
\ No newline at end of file
@@ -0,0 +1,47 @@
+use std::thread;
+use std::time::Instant;
+use log::info;
+use rand::Rng;
+use crate::server::cache::{Cache, Key};
+use crate::server::values::Value;
+
+const KEYS: usize = 100_000;
+const OPS_PER_THREAD: usize = 500_000;
+// share of operations which are writes, the rest are reads
+const WRITE_RATIO: f64 = 0.2;
+const TTL: Option<u64> = Some(3600);
+
+/// Measures throughput of the cache with growing number of threads hitting it at once.
+/// Run it with different `--cache-shards` to compare, with a single shard threads mostly wait for each other.
+pub fn run_bench_mode(cache: Cache) {
+    let keys: Vec<Key> = (0..KEYS).map(|i| format!("key-{i}")).collect();
+    let value = Value::from("x".repeat(64));
+    for key in &keys {
+        cache.put(key, &value, TTL);
+    }
+
+    let max_threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1).max(8);
+    let mut threads = 1;
+    while threads <= max_threads {
+        let started = Instant::now();
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    let mut rng = rand::rng();
+                    for _ in 0..OPS_PER_THREAD {
+                        let key = &keys[rng.random_range(0..KEYS)];
+                        if rng.random_bool(WRITE_RATIO) {
+                            cache.put(key, &value, TTL);
+                        } else {
+                            cache.get(key).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        let elapsed = started.elapsed();
+        let ops_per_sec = (threads * OPS_PER_THREAD) as f64 / elapsed.as_secs_f64();
+        info!("{threads} threads: {ops_per_sec:.0} ops/sec");
+        threads *= 2;
+    }
+}
@@ -1,65 +1,817 @@
 use std::cmp::Reverse;
 use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
 use std::ops::Add;
-use std::sync::{Arc, Mutex};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use log::debug;
 use priority_queue::PriorityQueue;
+use serde::{Deserialize, Serialize};
+use crate::server::eviction::{EvictionPolicy, EvictionPolicyKind, RandomKeySet};
+use crate::server::values::{Field, Hash, List, ScoredMember, Set, SortedSet, StoredValue, Value};
 
 
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+const SWEEP_SAMPLE_SIZE: usize = 20;
+// sampling of a shard is repeated while more than this share of sampled keys was expired
+const SWEEP_REPEAT_EXPIRED_PERCENT: usize = 25;
+// max time of a single sweep over all shards
+const SWEEP_TIME_BUDGET: Duration = Duration::from_millis(25);
+
 pub type Key = String;
-pub type Value = String;
+
+/// Snapshot of a single cached key, used to move keys between nodes.
+/// `ttl` is the time left until expiration, in seconds, none for keys which never expire.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub key: Key,
+    pub value: StoredValue,
+    pub ttl: Option<u64>,
+}
+
+/// Value together with its version. Version changes on every write of the value
+/// and never repeats for the same key, so it can be used for compare-and-swap.
+#[derive(Debug, Clone)]
+pub struct VersionedValue {
+    pub value: Value,
+    pub version: u64,
+}
+
+struct StoredEntry {
+    value: StoredValue,
+    version: u64,
+}
+
+/// Condition for a write to happen.
+#[derive(Debug, Clone, Copy)]
+pub enum PutCondition {
+    Absent,
+    Present,
+    // key exists and has the given version
+    Version(u64),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CacheError {
+    // value of the key can't be parsed as a number
+    NotANumber,
+    // result doesn't fit into i64 or is not a finite float
+    Overflow,
+    // operation is not supported by the type of the value, e.g. list operation on a string
+    WrongType,
+}
+
+#[derive(Debug)]
+pub struct CacheConfig {
+    // keys are spread over shards, each one has its own lock and eviction policy
+    pub shards: usize,
+    // limits apply to all the shards together
+    pub max_entries: Option<usize>,
+    // approximate limit, counts only sizes of keys and values
+    pub max_bytes: Option<usize>,
+    pub eviction_policy: EvictionPolicyKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub used_bytes: usize,
+    pub evictions: u64,
+    pub expirations: u64,
+}
 
 pub struct Cache {
-    hash_map: Arc<Mutex<HashMap<Key, Value>>>,
-    // this design makes cache itself tightly coupled to eviction mechanism
-    // this is not ideal, and should be refactored out later
-    ttl_queue: Arc<Mutex<PriorityQueue<Key, Reverse<SystemTime>>>>,
+    shards: Arc<Vec<Mutex<Storage>>>,
+    // seeded separately from bucket hashing, otherwise keys of one bucket would share a single shard
+    hash_builder: RandomState,
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+    totals: Arc<CacheTotals>,
+}
+
+// entries and sizes of all the shards together, updated by shards under their own locks
+#[derive(Default)]
+struct CacheTotals {
+    entries: AtomicUsize,
+    used_bytes: AtomicUsize,
+}
+
+// all the structures of a shard are kept under the same lock, so they never disagree on which keys exist
+struct Storage {
+    hash_map: HashMap<Key, StoredEntry>,
+    // the last version given to a value in this shard
+    last_version: u64,
+    // expiration time of each key with TTL
+    ttl_queue: PriorityQueue<Key, Reverse<SystemTime>>,
+    // keys with TTL, sampled by the sweeper to find expired ones
+    volatile_keys: RandomKeySet,
+    // decides which keys are evicted when cache is over its limits
+    eviction_policy: Box<dyn EvictionPolicy>,
+    totals: Arc<CacheTotals>,
+    evictions: u64,
+    expirations: u64,
 }
 
 impl Cache {
-    pub fn new() -> Cache {
-        let hash_map = Arc::new(Mutex::new(HashMap::new()));
-        let ttl_queue: Arc<Mutex<PriorityQueue<Key, Reverse<SystemTime>>>> = Arc::new(Mutex::new(PriorityQueue::new()));
+    pub fn new(config: CacheConfig) -> Cache {
+        let num_shards = config.shards.max(1);
+        let totals = Arc::new(CacheTotals::default());
+        let shards: Arc<Vec<Mutex<Storage>>> = Arc::new((0..num_shards)
+            .map(|_| Mutex::new(Storage {
+                hash_map: HashMap::new(),
+                last_version: 0,
+                ttl_queue: PriorityQueue::new(),
+                volatile_keys: RandomKeySet::default(),
+                eviction_policy: config.eviction_policy.create(),
+                totals: Arc::clone(&totals),
+                evictions: 0,
+                expirations: 0,
+            }))
+            .collect());
 
-        let hash_map_clone = hash_map.clone();
-        let ttl_queue_clone = ttl_queue.clone();
+        // sweeper holds only a weak reference, so it stops once the cache is dropped
+        let sweeper_shards = Arc::downgrade(&shards);
+        thread::spawn(move || run_expiry_sweeper(sweeper_shards));
 
-        thread::spawn(move || {
-            loop {
-                thread::sleep(Duration::from_secs(2));
+        Cache {
+            shards,
+            hash_builder: RandomState::new(),
+            max_entries: config.max_entries,
+            max_bytes: config.max_bytes,
+            totals,
+        }
+    }
 
+    pub fn put(&self, key: &Key, value: &Value, ttl: Option<u64>) {
+        let mut storage = self.lock_shard(key);
+        // overwriting a key without TTL makes it persistent, same as in Redis
+        let expiration_time = ttl.map(|ttl| SystemTime::now().add(Duration::from_secs(ttl)));
+        self.store(&mut storage, key, StoredValue::String(value.clone()), expiration_time);
+    }
+
+    /// Stores the key moved from another node.
+    pub fn put_entry(&self, entry: CacheEntry) {
+        let mut storage = self.lock_shard(&entry.key);
+        let expiration_time = entry.ttl.map(|ttl| SystemTime::now().add(Duration::from_secs(ttl)));
+        self.store(&mut storage, &entry.key, entry.value, expiration_time);
+    }
+
+    /// Puts the value only if the key satisfies the condition.
+    /// Returns the new version of the value, or none if the condition didn't hold.
+    pub fn put_if(&self, key: &Key, value: &Value, ttl: Option<u64>, condition: PutCondition) -> Option<u64> {
+        let mut storage = self.lock_shard(key);
+        storage.expire_if_needed(key, SystemTime::now());
+        let current_version = storage.hash_map.get(key).map(|current| current.version);
+        let satisfied = match condition {
+            PutCondition::Absent => current_version.is_none(),
+            PutCondition::Present => current_version.is_some(),
+            PutCondition::Version(version) => current_version == Some(version),
+        };
+        if !satisfied {
+            return None;
+        }
+        let expiration_time = ttl.map(|ttl| SystemTime::now().add(Duration::from_secs(ttl)));
+        Some(self.store(&mut storage, key, StoredValue::String(value.clone()), expiration_time))
+    }
+
+    /// Atomically adds `delta` to the integer value of the key and returns the new value.
+    /// Missing key is treated as 0 and created with `ttl`, existing key keeps its TTL.
+    pub fn incr_by(&self, key: &Key, delta: i64, ttl: Option<u64>) -> Result<i64, CacheError> {
+        self.modify_value(key, ttl, || StoredValue::String(Value::from("0")), |value| {
+            let value = value.as_string_mut()?;
+            let current = parse_number::<i64>(value)?;
+            let new_value = current.checked_add(delta).ok_or(CacheError::Overflow)?;
+            *value = Value::from(new_value.to_string());
+            Ok(new_value)
+        })
+    }
+
+    /// Adds `delta` to the value of an existing key, which has to be an unsigned integer, like memcached counters.
+    /// Increment wraps around at 2^64, decrement stops at 0. Returns none if there is no such key.
+    pub fn incr_existing(&self, key: &Key, delta: u64, decrement: bool) -> Result<Option<u64>, CacheError> {
+        let mut storage = self.lock_shard(key);
+        storage.expire_if_needed(key, SystemTime::now());
+        if !storage.hash_map.contains_key(key) {
+            return Ok(None);
+        }
+        self.modify_locked_value(&mut storage, key, None, || unreachable!("key exists"), |value| {
+            let value = value.as_string_mut()?;
+            let current = parse_number::<u64>(value)?;
+            let new_value = if decrement { current.saturating_sub(delta) } else { current.wrapping_add(delta) };
+            *value = Value::from(new_value.to_string());
+            Ok(Some(new_value))
+        })
+    }
+
+    /// Same as `incr_by`, for float values.
+    pub fn incr_by_float(&self, key: &Key, delta: f64, ttl: Option<u64>) -> Result<f64, CacheError> {
+        self.modify_value(key, ttl, || StoredValue::String(Value::from("0")), |value| {
+            let value = value.as_string_mut()?;
+            let current = parse_number::<f64>(value)?;
+            let new_value = current + delta;
+            if !new_value.is_finite() {
+                return Err(CacheError::Overflow);
+            }
+            *value = Value::from(new_value.to_string());
+            Ok(new_value)
+        })
+    }
+
+    pub fn get(&self, key: &Key) -> Result<Option<VersionedValue>, CacheError> {
+        self.read_value(key, |entry| {
+            let Some(entry) = entry else {
+                return Ok(None);
+            };
+            let value = entry.value.as_string()?.clone();
+            Ok(Some(VersionedValue { value, version: entry.version }))
+        })
+    }
+
+    /// Adds values to the head or to the tail of the list, creating it with `ttl` if it doesn't exist.
+    /// Returns the new length of the list.
+    pub fn push(&self, key: &Key, values: Vec<Value>, to_front: bool, ttl: Option<u64>) -> Result<usize, CacheError> {
+        self.modify_value(key, ttl, || StoredValue::List(List::default()), |value| {
+            let list = value.as_list_mut()?;
+            for value in values {
+                if to_front {
+                    list.push_front(value);
+                } else {
+                    list.push_back(value);
+                }
+            }
+            Ok(list.len())
+        })
+    }
+
+    pub fn pop(&self, key: &Key, from_front: bool) -> Result<Option<Value>, CacheError> {
+        self.modify_value(key, None, || StoredValue::List(List::default()), |value| {
+            let list = value.as_list_mut()?;
+            Ok(if from_front { list.pop_front() } else { list.pop_back() })
+        })
+    }
+
+    /// Returns items of the list between `start` and `stop` inclusive, negative indexes count from the end.
+    pub fn list_range(&self, key: &Key, start: i64, stop: i64) -> Result<Vec<Value>, CacheError> {
+        self.read_value(key, |entry| match entry {
+            Some(entry) => Ok(entry.value.as_list()?.range(start, stop)),
+            None => Ok(Vec::new()),
+        })
+    }
+
+    pub fn list_len(&self, key: &Key) -> Result<usize, CacheError> {
+        self.read_value(key, |entry| match entry {
+            Some(entry) => Ok(entry.value.as_list()?.len()),
+            None => Ok(0),
+        })
+    }
+
+    /// Keeps only items of the list between `start` and `stop` inclusive.
+    pub fn list_trim(&self, key: &Key, start: i64, stop: i64) -> Result<(), CacheError> {
+        self.modify_value(key, None, || StoredValue::List(List::default()), |value| {
+            value.as_list_mut()?.trim(start, stop);
+            Ok(())
+        })
+    }
+
+    pub fn exists(&self, key: &Key) -> bool {
+        let mut storage = self.lock_shard(key);
+        !storage.expire_if_needed(key, SystemTime::now()) && storage.hash_map.contains_key(key)
+    }
+
+    pub fn remove(&self, key: &Key) -> bool {
+        self.lock_shard(key).remove(key)
+    }
+
+    /// Returns seconds left until the key expires, none for a key without TTL,
+    /// or none at all if the key doesn't exist.
+    pub fn get_ttl(&self, key: &Key) -> Option<Option<u64>> {
+        let mut storage = self.lock_shard(key);
+        let cur_time = SystemTime::now();
+        if storage.expire_if_needed(key, cur_time) || !storage.hash_map.contains_key(key) {
+            return None;
+        }
+        Some(storage.ttl_queue.get_priority(key)
+            .map(|expiration_time| remaining_secs(expiration_time.0, cur_time)))
+    }
+
+    /// Sets new expiration time of existing key, returns false if there is no such key.
+    /// Key is removed right away if the time has already passed.
+    pub fn expire(&self, key: &Key, expiration_time: SystemTime) -> bool {
+        let mut storage = self.lock_shard(key);
+        if storage.expire_if_needed(key, SystemTime::now()) || !storage.hash_map.contains_key(key) {
+            return false;
+        }
+        storage.set_expiration(key, Some(expiration_time));
+        storage.eviction_policy.on_expiration_change(key, Some(expiration_time));
+        storage.expire_if_needed(key, SystemTime::now());
+        true
+    }
+
+    /// Removes TTL of the key, returns false if there is no such key or it has no TTL.
+    pub fn persist(&self, key: &Key) -> bool {
+        let mut storage = self.lock_shard(key);
+        if storage.expire_if_needed(key, SystemTime::now()) || !storage.ttl_queue.contains(key) {
+            return false;
+        }
+        storage.set_expiration(key, None);
+        storage.eviction_policy.on_expiration_change(key, None);
+        true
+    }
+
+    // shards are locked one by one, so stats under concurrent writes are approximate
+    pub fn get_stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            entries: self.totals.entries.load(Ordering::Relaxed),
+            used_bytes: self.totals.used_bytes.load(Ordering::Relaxed),
+            evictions: 0,
+            expirations: 0,
+        };
+        for shard in self.shards.iter() {
+            let storage = shard.lock().unwrap();
+            stats.evictions += storage.evictions;
+            stats.expirations += storage.expirations;
+        }
+        stats
+    }
+
+    pub fn get_entries<F>(&self, filter: F) -> Vec<CacheEntry>
+    where
+        F: Fn(&Key) -> bool,
+    {
+        let cur_time = SystemTime::now();
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            let storage = shard.lock().unwrap();
+            entries.extend(storage.hash_map.iter()
+                .filter(|(key, _)| !storage.is_expired(key, cur_time) && filter(key))
+                .map(|(key, entry)| {
+                    let ttl = storage.ttl_queue.get_priority(key)
+                        .map(|expiration_time| remaining_secs(expiration_time.0, cur_time));
+                    CacheEntry { key: key.clone(), value: entry.value.clone(), ttl }
+                }));
+        }
+        entries
+    }
+
+    /// Sets fields of the hash, creating it with `ttl` if it doesn't exist. Returns number of new fields.
+    pub fn hash_set(&self, key: &Key, fields: HashMap<Field, Value>, ttl: Option<u64>) -> Result<usize, CacheError> {
+        self.modify_value(key, ttl, || StoredValue::Hash(Hash::default()), |value| {
+            let hash = value.as_hash_mut()?;
+            Ok(fields.into_iter().map(|(field, value)| hash.set(field, value)).filter(|added| *added).count())
+        })
+    }
+
+    pub fn hash_get(&self, key: &Key, field: &Field) -> Result<Option<Value>, CacheError> {
+        self.read_value(key, |entry| match entry {
+            Some(entry) => Ok(entry.value.as_hash()?.get(field).cloned()),
+            None => Ok(None),
+        })
+    }
+
+    /// Removes fields from the hash, returns number of removed ones.
+    pub fn hash_delete(&self, key: &Key, fields: &[Field]) -> Result<usize, CacheError> {
+        self.modify_value(key, None, || StoredValue::Hash(Hash::default()), |value| {
+            let hash = value.as_hash_mut()?;
+            Ok(fields.iter().filter(|field| hash.delete(field)).count())
+        })
+    }
+
+    pub fn hash_get_all(&self, key: &Key) -> Result<HashMap<Field, Value>, CacheError> {
+        self.read_value(key, |entry| match entry {
+            Some(entry) => Ok(entry.value.as_hash()?.get_all()),
+            None => Ok(HashMap::new()),
+        })
+    }
+
+    /// Atomically adds `delta` to the integer value of the field, missing field is treated as 0.
+    pub fn hash_incr_by(&self, key: &Key, field: &Field, delta: i64, ttl: Option<u64>) -> Result<i64, CacheError> {
+        self.modify_value(key, ttl, || StoredValue::Hash(Hash::default()), |value| {
+            let hash = value.as_hash_mut()?;
+            let current = match hash.get(field) {
+                Some(value) => parse_number::<i64>(value)?,
+                None => 0,
+            };
+            let new_value = current.checked_add(delta).ok_or(CacheError::Overflow)?;
+            hash.set(field.clone(), Value::from(new_value.to_string()));
+            Ok(new_value)
+        })
+    }
+
+    pub fn hash_exists(&self, key: &Key, field: &Field) -> Result<bool, CacheError> {
+        self.read_value(key, |entry| match entry {
+            Some(entry) => Ok(entry.value.as_hash()?.contains(field)),
+            None => Ok(false),
+        })
+    }
+
+    /// Adds members to the set, creating it with `ttl` if it doesn't exist. Returns number of new members.
+    pub fn set_add(&self, key: &Key, members: Vec<Value>, ttl: Option<u64>) -> Result<usize, CacheError> {
+        self.modify_value(key, ttl, || StoredValue::Set(Set::default()), |value| {
+            let set = value.as_set_mut()?;
+            Ok(members.into_iter().map(|member| set.add(member)).filter(|added| *added).count())
+        })
+    }
+
+    /// Removes members from the set, returns number of removed ones.
+    pub fn set_remove(&self, key: &Key, members: &[Value]) -> Result<usize, CacheError> {
+        self.modify_value(key, None, || StoredValue::Set(Set::default()), |value| {
+            let set = value.as_set_mut()?;
+            Ok(members.iter().filter(|member| set.remove(member)).count())
+        })
+    }
+
+    pub fn set_members(&self, key: &Key) -> Result<Vec<Value>, CacheError> {
+        self.read_value(key, |entry| match entry {
+            Some(entry) => Ok(entry.value.as_set()?.members()),
+            None => Ok(Vec::new()),
+        })
+    }
+
+    pub fn set_is_member(&self, key: &Key, member: &Value) -> Result<bool, CacheError> {
+        self.read_value(key, |entry| match entry {
+            Some(entry) => Ok(entry.value.as_set()?.contains(member)),
+            None => Ok(false),
+        })
+    }
+
+    pub fn set_len(&self, key: &Key) -> Result<usize, CacheError> {
+        self.read_value(key, |entry| match entry {
+            Some(entry) => Ok(entry.value.as_set()?.len()),
+            None => Ok(0),
+        })
+    }
+
+    /// Adds members to the sorted set or updates their scores, creating the set with `ttl` if it doesn't exist.
+    /// Returns number of new members. Nothing is added if any score is NaN, as it can't be ordered.
+    pub fn sorted_set_add(&self, key: &Key, members: Vec<ScoredMember>, ttl: Option<u64>) -> Result<usize, CacheError> {
+        if members.iter().any(|scored| scored.score.is_nan()) {
+            return Err(CacheError::NotANumber);
+        }
+        self.modify_value(key, ttl, || StoredValue::SortedSet(SortedSet::default()), |value| {
+            let sorted_set = value.as_sorted_set_mut()?;
+            Ok(members.into_iter()
+                .map(|scored| sorted_set.add(scored.member, scored.score))
+                .filter(|added| *added)
+                .count())
+        })
+    }
+
+    /// Returns members between positions `start` and `stop` inclusive, ordered by score.
+    pub fn sorted_set_range(&self, key: &Key, start: i64, stop: i64) -> Result<Vec<ScoredMember>, CacheError> {
+        self.read_value(key, |entry| match entry {
+            Some(entry) => Ok(entry.value.as_sorted_set()?.range(start, stop)),
+            None => Ok(Vec::new()),
+        })
+    }
+
+    pub fn sorted_set_range_by_score(&self, key: &Key, min: f64, max: f64) -> Result<Vec<ScoredMember>, CacheError> {
+        self.read_value(key, |entry| match entry {
+            Some(entry) => Ok(entry.value.as_sorted_set()?.range_by_score(min, max)),
+            None => Ok(Vec::new()),
+        })
+    }
+
+    pub fn sorted_set_rank(&self, key: &Key, member: &Value) -> Result<Option<usize>, CacheError> {
+        self.read_value(key, |entry| match entry {
+            Some(entry) => Ok(entry.value.as_sorted_set()?.rank(member)),
+            None => Ok(None),
+        })
+    }
+
+    /// Atomically adds `delta` to the score of the member and returns the new score, missing member starts from 0.
+    pub fn sorted_set_incr_by(&self, key: &Key, member: &Value, delta: f64, ttl: Option<u64>) -> Result<f64, CacheError> {
+        self.modify_value(key, ttl, || StoredValue::SortedSet(SortedSet::default()), |value| {
+            value.as_sorted_set_mut()?.incr_by(member.clone(), delta)
+        })
+    }
+
+    fn read_value<T, F>(&self, key: &Key, read: F) -> Result<T, CacheError>
+    where
+        F: FnOnce(Option<&StoredEntry>) -> Result<T, CacheError>,
+    {
+        let mut storage = self.lock_shard(key);
+        storage.expire_if_needed(key, SystemTime::now());
+        let result = read(storage.hash_map.get(key));
+        if result.is_ok() && storage.hash_map.contains_key(key) {
+            storage.eviction_policy.on_access(key);
+        }
+        result
+    }
+
+    // read-modify-write of a single key under its shard lock. Missing key is created with `create` and `ttl`,
+    // existing key keeps its TTL. Value is left as it was if `modify` fails
+    fn modify_value<T, C, F>(&self, key: &Key, ttl: Option<u64>, create: C, modify: F) -> Result<T, CacheError>
+    where
+        C: FnOnce() -> StoredValue,
+        F: FnOnce(&mut StoredValue) -> Result<T, CacheError>,
+    {
+        let mut storage = self.lock_shard(key);
+        storage.expire_if_needed(key, SystemTime::now());
+        self.modify_locked_value(&mut storage, key, ttl, create, modify)
+    }
+
+    // same as `modify_value`, for a shard which is already locked
+    fn modify_locked_value<T, C, F>(&self, storage: &mut Storage, key: &Key, ttl: Option<u64>, create: C, modify: F) -> Result<T, CacheError>
+    where
+        C: FnOnce() -> StoredValue,
+        F: FnOnce(&mut StoredValue) -> Result<T, CacheError>,
+    {
+        let created = !storage.hash_map.contains_key(key);
+        let expiration_time = if created {
+            let value = create();
+            storage.totals.add(1, key.len() + value.size());
+            storage.hash_map.insert(key.to_string(), StoredEntry { value, version: 0 });
+            let expiration_time = ttl.map(|ttl| SystemTime::now().add(Duration::from_secs(ttl)));
+            storage.set_expiration(key, expiration_time);
+            expiration_time
+        } else {
+            storage.ttl_queue.get_priority(key).map(|expiration_time| expiration_time.0)
+        };
+
+        let version = storage.next_version();
+        let entry = storage.hash_map.get_mut(key).unwrap();
+        let size_before = entry.value.size();
+        let result = modify(&mut entry.value);
+        let size_after = entry.value.size();
+        if result.is_ok() {
+            entry.version = version;
+        }
+        let is_empty = entry.value.is_empty();
+        storage.totals.resize(size_before, size_after);
+
+        if is_empty || (created && result.is_err()) {
+            storage.remove(key);
+        } else {
+            storage.eviction_policy.on_insert(key, expiration_time);
+            self.evict_over_limits(storage);
+        }
+        result
+    }
+
+    // returns the new version of the value
+    fn store(&self, storage: &mut Storage, key: &Key, value: StoredValue, expiration_time: Option<SystemTime>) -> u64 {
+        storage.totals.add(1, key.len() + value.size());
+        let version = storage.next_version();
+        if let Some(previous) = storage.hash_map.insert(key.to_string(), StoredEntry { value, version }) {
+            storage.totals.remove(1, key.len() + previous.value.size());
+        }
+        storage.set_expiration(key, expiration_time);
+        storage.eviction_policy.on_insert(key, expiration_time);
+        self.evict_over_limits(storage);
+        version
+    }
+
+    fn lock_shard(&self, key: &Key) -> MutexGuard<'_, Storage> {
+        let shard = self.hash_builder.hash_one(key) as usize % self.shards.len();
+        self.shards[shard].lock().unwrap()
+    }
+
+    // Victims are picked by the policy of the shard which went over limits, so eviction order holds within a shard.
+    // If the shard has nothing left to evict, other shards are evicted from, skipping the ones locked right now,
+    // so limits can be exceeded until their next write
+    fn evict_over_limits(&self, storage: &mut Storage) {
+        if !self.evict_from_shard(storage) {
+            return;
+        }
+        for shard in self.shards.iter() {
+            let Ok(mut other_storage) = shard.try_lock() else {
+                continue;
+            };
+            if !self.evict_from_shard(&mut other_storage) {
+                return;
+            }
+        }
+    }
+
+    // returns whether cache is still over limits
+    fn evict_from_shard(&self, storage: &mut Storage) -> bool {
+        while self.is_over_limits() {
+            let Some(key) = storage.eviction_policy.pick_victim() else {
+                return true;
+            };
+            debug!("Cache is over limits, evicting {key}");
+            storage.remove(&key);
+            storage.evictions += 1;
+        }
+        false
+    }
+
+    fn is_over_limits(&self) -> bool {
+        self.max_entries.is_some_and(|max_entries| self.totals.entries.load(Ordering::Relaxed) > max_entries)
+            || self.max_bytes.is_some_and(|max_bytes| self.totals.used_bytes.load(Ordering::Relaxed) > max_bytes)
+    }
+}
+
+impl CacheTotals {
+    fn add(&self, entries: usize, bytes: usize) {
+        self.entries.fetch_add(entries, Ordering::Relaxed);
+        self.used_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn remove(&self, entries: usize, bytes: usize) {
+        self.entries.fetch_sub(entries, Ordering::Relaxed);
+        self.used_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    // added before the old size is removed, so the total never goes below zero
+    fn resize(&self, size_before: usize, size_after: usize) {
+        self.used_bytes.fetch_add(size_after, Ordering::Relaxed);
+        self.used_bytes.fetch_sub(size_before, Ordering::Relaxed);
+    }
+}
+
+impl Storage {
+    fn is_expired(&self, key: &Key, cur_time: SystemTime) -> bool {
+        self.ttl_queue.get_priority(key).is_some_and(|expiration_time| expiration_time.0 <= cur_time)
+    }
+
+    // versions start from current time in microseconds, so a key deleted and created again,
+    // or moved to another node, never gets a version it already had
+    fn next_version(&mut self) -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_micros() as u64;
+        self.last_version = (self.last_version + 1).max(now);
+        self.last_version
+    }
+
+    fn set_expiration(&mut self, key: &Key, expiration_time: Option<SystemTime>) {
+        match expiration_time {
+            Some(expiration_time) => {
+                // pushing to the queue existing key overwrites its expiration time
+                self.ttl_queue.push(key.to_string(), Reverse(expiration_time));
+                self.volatile_keys.insert(key);
+            }
+            None => {
+                self.ttl_queue.remove(key);
+                self.volatile_keys.remove(key);
+            }
+        }
+    }
+
+    // removes the key if its TTL has passed, returns whether it was removed
+    fn expire_if_needed(&mut self, key: &Key, cur_time: SystemTime) -> bool {
+        if !self.is_expired(key, cur_time) {
+            return false;
+        }
+        debug!("{key} expired, removing");
+        self.remove(key);
+        self.expirations += 1;
+        true
+    }
+
+    fn remove(&mut self, key: &Key) -> bool {
+        self.ttl_queue.remove(key);
+        self.volatile_keys.remove(key);
+        self.eviction_policy.on_delete(key);
+        match self.hash_map.remove(key) {
+            Some(previous) => {
+                self.totals.remove(1, key.len() + previous.value.size());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn parse_number<T: FromStr>(value: &Value) -> Result<T, CacheError> {
+    value.as_str()
+        .and_then(|string| string.parse::<T>().ok())
+        .ok_or(CacheError::NotANumber)
+}
+
+// rounded to the closest second, so a key put with TTL of 10 seconds reports 10 right after
+fn remaining_secs(expiration_time: SystemTime, cur_time: SystemTime) -> u64 {
+    let remaining = expiration_time.duration_since(cur_time).unwrap_or(Duration::ZERO);
+    (remaining.as_millis() as u64 + 500) / 1000
+}
+
+// Expired keys are removed lazily when they are read, this sweeper cleans up the ones nobody reads.
+// Like in Redis, it checks random keys with TTL, and keeps going while a big share of them turn out expired.
+fn run_expiry_sweeper(shards: Weak<Vec<Mutex<Storage>>>) {
+    loop {
+        thread::sleep(SWEEP_INTERVAL);
+        let Some(shards) = shards.upgrade() else {
+            debug!("Cache is dropped, stopping expiry sweeper");
+            return;
+        };
+
+        let started = Instant::now();
+        for shard in shards.iter() {
+            loop {
+                let mut storage = shard.lock().unwrap();
                 let cur_time = SystemTime::now();
-                while let Some((key, expiration_time)) = ttl_queue_clone.lock().unwrap().peek() {
-                    if expiration_time.0 >= cur_time {
-                        debug!("It's not yet time to expire {key}");
+                let mut sampled = 0;
+                let mut expired = 0;
+                while sampled < SWEEP_SAMPLE_SIZE {
+                    let Some(key) = storage.volatile_keys.random_key().cloned() else {
                         break;
+                    };
+                    sampled += 1;
+                    if storage.expire_if_needed(&key, cur_time) {
+                        expired += 1;
                     }
-                    debug!("{key} expired, removing");
-                    hash_map_clone.lock().unwrap().remove(key);
-                    ttl_queue_clone.lock().unwrap().pop();
+                }
+                drop(storage);
+
+                // lock is released between rounds, so requests to the shard are not blocked for long
+                if expired * 100 <= sampled * SWEEP_REPEAT_EXPIRED_PERCENT || started.elapsed() > SWEEP_TIME_BUDGET {
+                    break;
                 }
             }
-        });
+        }
+    }
+}
 
-        Cache {
-            hash_map,
-            ttl_queue,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(shards: usize, max_entries: Option<usize>, max_bytes: Option<usize>) -> Cache {
+        Cache::new(CacheConfig { shards, max_entries, max_bytes, eviction_policy: EvictionPolicyKind::Lru })
+    }
+
+    fn key(name: &str) -> Key {
+        name.to_string()
+    }
+
+    #[test]
+    fn entry_limit_applies_to_all_shards_together() {
+        let cache = cache(16, Some(3), None);
+        for index in 0..10 {
+            cache.put(&format!("key-{index}"), &Value::from("value"), None);
         }
+        let stats = cache.get_stats();
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.evictions, 7);
     }
-    pub fn put(&mut self, key: &Key, value: &Value, ttl: u64) {
-        self.hash_map.lock().unwrap().insert(key.to_string(), value.to_string());
-        let expiration_time = SystemTime::now().add(Duration::from_secs(ttl));
-        // pushing to the queue existing key overwrites its expiration time
-        self.ttl_queue.lock().unwrap().push(key.to_string(), Reverse(expiration_time));
+
+    #[test]
+    fn byte_limit_applies_to_all_shards_together() {
+        // every key takes 5 bytes with its value
+        let cache = cache(16, None, Some(12));
+        for index in 0..10 {
+            cache.put(&format!("k{index}"), &Value::from("abc"), None);
+        }
+        let stats = cache.get_stats();
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.used_bytes, 10);
     }
 
-    pub fn get(&self, key: &Key) -> Option<Value> {
-        return self.hash_map.lock().unwrap().get(key).cloned();
+    #[test]
+    fn evicts_least_recently_used_key() {
+        let cache = cache(1, Some(2), None);
+        cache.put(&key("a"), &Value::from("1"), None);
+        cache.put(&key("b"), &Value::from("2"), None);
+        cache.get(&key("a")).unwrap();
+        cache.put(&key("c"), &Value::from("3"), None);
+        assert!(cache.exists(&key("a")));
+        assert!(!cache.exists(&key("b")));
+        assert!(cache.exists(&key("c")));
     }
 
-    pub fn exists(&self, key: &Key) -> bool {
-        return self.hash_map.lock().unwrap().contains_key(key);
+    #[test]
+    fn used_bytes_follow_overwrites_and_removals() {
+        let cache = cache(4, None, None);
+        cache.put(&key("string"), &Value::from("long value"), None);
+        cache.put(&key("string"), &Value::from("short"), None);
+        assert_eq!(cache.get_stats().used_bytes, "string".len() + "short".len());
+
+        cache.push(&key("list"), vec![Value::from("ab"), Value::from("cde")], false, None).unwrap();
+        cache.pop(&key("list"), true).unwrap();
+        assert_eq!(cache.get_stats().used_bytes, "string".len() + "short".len() + "list".len() + "cde".len());
+
+        cache.pop(&key("list"), true).unwrap();
+        cache.remove(&key("string"));
+        let stats = cache.get_stats();
+        assert_eq!(stats.entries, 0);
+        assert_eq!(stats.used_bytes, 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn incr_existing_keeps_memcached_semantics() {
+        let cache = cache(1, None, None);
+        assert_eq!(cache.incr_existing(&key("counter"), 1, false).ok(), Some(None));
+        assert!(!cache.exists(&key("counter")));
+
+        cache.put(&key("counter"), &Value::from("5"), None);
+        assert_eq!(cache.incr_existing(&key("counter"), 7, true).ok(), Some(Some(0)));
+        assert_eq!(cache.incr_existing(&key("counter"), u64::MAX, false).ok(), Some(Some(u64::MAX)));
+        assert_eq!(cache.incr_existing(&key("counter"), 2, false).ok(), Some(Some(1)));
+
+        cache.put(&key("text"), &Value::from("abc"), None);
+        assert!(matches!(cache.incr_existing(&key("text"), 1, false), Err(CacheError::NotANumber)));
+    }
+
+    #[test]
+    fn sorted_set_add_rejects_nan_scores() {
+        let cache = cache(1, None, None);
+        let members = vec![
+            ScoredMember { member: Value::from("a"), score: 1.0 },
+            ScoredMember { member: Value::from("b"), score: f64::NAN },
+        ];
+        assert!(cache.sorted_set_add(&key("scores"), members, None).is_err());
+        assert!(!cache.exists(&key("scores")));
+    }
+}
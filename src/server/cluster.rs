@@ -1,72 +1,215 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::hash::{DefaultHasher, Hash, Hasher};
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::net::{SocketAddr, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
 use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 use crate::server::cache::Key;
 use crate::server::commands::{CmdResponseEnum, CommandsEnum};
-use crate::server::commands::CommandsEnum::{GetClusterState, JoinCluster, UpdateClusterState};
+use crate::server::requests::{ReqResponseEnum, RequestsEnum};
+use crate::server::commands::CommandsEnum::{ClusterStateApplied, GetClusterState, JoinCluster};
+use crate::server::connection_pool::{ConnectionPool, ForwardingConfig};
+use crate::server::framing;
 
 pub type NodeId = String;
 pub type BucketId = u64;
 
+// nodes with their addresses, primary and replicas of each bucket
+type ClusterState = (HashMap<NodeId, NodeAddrs>, HashMap<BucketId, NodeId>, HashMap<BucketId, Vec<NodeId>>);
+// none while the node can't be reached, it is opened again for the next command
+type NodeConnection = Arc<Mutex<Option<TcpStream>>>;
+
+#[derive(Debug)]
+pub struct ReplicationConfig {
+    // number of nodes keeping a copy of each bucket, in addition to its primary
+    pub replicas: usize,
+    // allows replicas to answer reads, which might return slightly stale values
+    pub read_from_replica: bool,
+}
+
+/// Addresses other nodes and clients reach the node at. They are configured on the node and advertised
+/// when it joins, as addresses of its connections are different from its listeners, and might not be reachable behind NAT.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct NodeAddrs {
+    pub server: SocketAddr,
+    pub client: SocketAddr,
+}
+
 pub struct Cluster {
     pub self_node_id: NodeId,
+    pub self_addrs: NodeAddrs,
+    // node to join the cluster through, none if this node starts a new cluster
+    leader_addr: Option<SocketAddr>,
     num_buckets: u64,
+    replication: ReplicationConfig,
+    // primary node of each bucket
     bucket_node_assignments: Arc<Mutex<HashMap<BucketId, NodeId>>>,
-    node_connections: Arc<Mutex<HashMap<NodeId, Arc<Mutex<TcpStream>>>>>,
+    bucket_replica_assignments: Arc<Mutex<HashMap<BucketId, Vec<NodeId>>>>,
+    // buckets reassigned to other nodes, which are still served here until their keys are handed over
+    outgoing_buckets: Arc<Mutex<HashMap<BucketId, NodeId>>>,
+    // buckets assigned to this node, which are served by previous owner until its keys arrive
+    incoming_buckets: Arc<Mutex<HashMap<BucketId, NodeId>>>,
+    node_connections: Arc<Mutex<HashMap<NodeId, NodeConnection>>>,
+    // addresses advertised by other nodes
+    node_addrs: Arc<Mutex<HashMap<NodeId, NodeAddrs>>>,
+    // connections for client requests forwarded to other nodes, so they don't wait for cluster commands
+    forwarding_connections: ConnectionPool,
+    // id of the next forwarded request, its response has to carry the same id
+    next_forwarded_request_id: AtomicU64,
+    // time of last heartbeat received from each node
+    node_last_seen: Arc<Mutex<HashMap<NodeId, Instant>>>,
+    suspected_nodes: Arc<Mutex<HashSet<NodeId>>>,
+    // nodes whose connection was closed or failed, they stay suspected until they send heartbeats again
+    disconnected_nodes: Arc<Mutex<HashSet<NodeId>>>,
+    // number of the last write to each bucket, assigned by primary or applied by replica
+    replication_offsets: Arc<Mutex<HashMap<BucketId, u64>>>,
+    // held while a write is applied and replicated, so replicas receive writes to a bucket in the order of their offsets
+    bucket_write_locks: Vec<Mutex<()>>,
+    // replication offsets other nodes reported in their last heartbeat
+    node_replication_offsets: Arc<Mutex<HashMap<NodeId, HashMap<BucketId, u64>>>>,
 }
 
 impl Cluster {
+    /// Applies cluster state published by another node. Nodes which can't be connected to are still added,
+    /// but suspected right away, so failure detection removes them unless they send heartbeats.
+    /// Returns the error of the last such connection, after the whole state is applied.
     pub fn update_cluster_state(&self,
-                                nodes_to_ips_updated: HashMap<NodeId, SocketAddr>,
+                                nodes_to_addrs_updated: HashMap<NodeId, NodeAddrs>,
                                 buckets_to_nodes_updated: HashMap<BucketId, NodeId>,
-    ) {
+                                buckets_to_replicas_updated: HashMap<BucketId, Vec<NodeId>>,
+    ) -> io::Result<()> {
         // updating node connections
-        for node in self.node_connections.lock().unwrap().keys() {
-            if !nodes_to_ips_updated.contains_key(node) {
-                self.node_connections.lock().unwrap().remove(node);
+        self.node_connections.lock().unwrap().retain(|node, _| nodes_to_addrs_updated.contains_key(node));
+        self.node_addrs.lock().unwrap().retain(|node, _| nodes_to_addrs_updated.contains_key(node));
+        self.forwarding_connections.retain_nodes(|node| nodes_to_addrs_updated.contains_key(node));
+        self.node_last_seen.lock().unwrap().retain(|node, _| nodes_to_addrs_updated.contains_key(node));
+        let mut result = Ok(());
+        for (node, addrs) in nodes_to_addrs_updated {
+            if node == self.self_node_id {
+                continue;
+            }
+            self.forwarding_connections.add_node(node.clone(), addrs.server);
+            self.watch_node(&node);
+            self.node_addrs.lock().unwrap().insert(node.clone(), addrs);
+            if self.node_connections.lock().unwrap().contains_key(&node) {
+                continue;
+            }
+            match connect_to_node(addrs.server) {
+                Ok(connection) => {
+                    self.node_connections.lock().unwrap().insert(node, Arc::new(Mutex::new(Some(connection))));
+                }
+                Err(e) => {
+                    self.node_connections.lock().unwrap().insert(node.clone(), Arc::new(Mutex::new(None)));
+                    self.mark_node_disconnected(&node);
+                    result = Err(io::Error::new(e.kind(), format!("couldn't connect to node {node} at {}: {e}", addrs.server)));
+                }
             }
-        }
-        for (node, addr) in nodes_to_ips_updated {
-            self.node_connections.lock().unwrap().entry(node).or_insert_with(|| {
-                Arc::new(Mutex::new(TcpStream::connect(addr).expect("Couldn't connect to new node")))
-            });
         }
         // updating buckets
         for (bucket, node) in buckets_to_nodes_updated {
             self.bucket_node_assignments.lock().unwrap().insert(bucket, node);
         }
+        *self.bucket_replica_assignments.lock().unwrap() = buckets_to_replicas_updated;
+        result
     }
 }
 
 impl Cluster {
-    pub fn new(num_buckets: u64, self_node_id: NodeId, leader_ip: Option<SocketAddr>) -> Cluster {
+    pub fn new(num_buckets: u64,
+               replication: ReplicationConfig,
+               forwarding: ForwardingConfig,
+               self_node_id: NodeId,
+               self_addrs: NodeAddrs,
+               leader_ip: Option<SocketAddr>,
+    ) -> Cluster {
         let bucket_node_assignments = Arc::new(Mutex::new(HashMap::new()));
+        let bucket_replica_assignments = Arc::new(Mutex::new(HashMap::new()));
+        let outgoing_buckets = Arc::new(Mutex::new(HashMap::new()));
+        let incoming_buckets = Arc::new(Mutex::new(HashMap::new()));
         let node_connections = Arc::new(Mutex::new(HashMap::new()));
+        let node_addrs = Arc::new(Mutex::new(HashMap::new()));
+        let node_last_seen = Arc::new(Mutex::new(HashMap::new()));
+        let suspected_nodes = Arc::new(Mutex::new(HashSet::new()));
+        let disconnected_nodes = Arc::new(Mutex::new(HashSet::new()));
+        let replication_offsets = Arc::new(Mutex::new(HashMap::new()));
+        let node_replication_offsets = Arc::new(Mutex::new(HashMap::new()));
 
-        match leader_ip {
-            None => {
-                Self::init_self_bucket_nodes(&self_node_id, num_buckets, bucket_node_assignments.clone());
+        // node joining a cluster gets its buckets from the leader in `join`
+        if leader_ip.is_none() {
+            Self::init_self_bucket_nodes(&self_node_id, num_buckets, bucket_node_assignments.clone());
+        }
 
-                Cluster {
-                    self_node_id,
-                    num_buckets,
-                    bucket_node_assignments,
-                    node_connections,
-                }
-            }
-            Some(leader_node) => {
-                Self::handle_cluster_join(&self_node_id, leader_node, bucket_node_assignments.clone(), node_connections.clone());
-
-                Cluster {
-                    self_node_id,
-                    num_buckets,
-                    bucket_node_assignments,
-                    node_connections,
-                }
-            }
+        Cluster {
+            self_node_id,
+            self_addrs,
+            leader_addr: leader_ip,
+            num_buckets,
+            replication,
+            bucket_node_assignments,
+            bucket_replica_assignments,
+            outgoing_buckets,
+            incoming_buckets,
+            node_connections,
+            node_addrs,
+            forwarding_connections: ConnectionPool::new(forwarding),
+            next_forwarded_request_id: AtomicU64::new(0),
+            node_last_seen,
+            suspected_nodes,
+            disconnected_nodes,
+            replication_offsets,
+            bucket_write_locks: (0..num_buckets).map(|_| Mutex::new(())).collect(),
+            node_replication_offsets,
+        }
+    }
+
+    /// Joins the cluster through the leader, if one was given. Leader connects back to
+    /// the cluster port of this node, so it has to accept connections already.
+    /// Cluster state is locked only to apply what the leader answers, as the leader and other nodes
+    /// send commands to this node while it joins, and processing them needs the state too.
+    pub fn join(cluster: &Arc<RwLock<Cluster>>) -> io::Result<()> {
+        let (self_node_id, self_addrs, leader_addr) = {
+            let cluster = cluster.read().unwrap();
+            let Some(leader_addr) = cluster.leader_addr else {
+                return Ok(());
+            };
+            (cluster.self_node_id.clone(), cluster.self_addrs, leader_addr)
+        };
+        // other nodes connect to the advertised address, so joining with one nobody can reach is refused here
+        connect_to_node(self_addrs.server).map_err(|e| {
+            io::Error::new(e.kind(), format!("advertised server address {} isn't reachable: {e}", self_addrs.server))
+        })?;
+        let mut leader = connect_to_node(leader_addr)?;
+
+        let (nodes_to_addrs, buckets_to_nodes, buckets_to_replicas) = request_cluster_state(&mut leader, &GetClusterState {})?;
+        info!("Received cluster state, nodes: {nodes_to_addrs:?}, buckets: {buckets_to_nodes:?}");
+        // opens connections to all the existing nodes
+        if let Err(e) = cluster.write().unwrap().update_cluster_state(nodes_to_addrs, buckets_to_nodes, buckets_to_replicas) {
+            warn!("Joining without a connection to every node: {e}");
+        }
+
+        let command = JoinCluster { node_id: self_node_id.clone(), addrs: self_addrs };
+        let (nodes_to_addrs, buckets_to_nodes, buckets_to_replicas) = request_cluster_state(&mut leader, &command)?;
+        let buckets_to_manage: Vec<BucketId> = buckets_to_nodes.iter()
+            .filter(|(_, node_id)| node_id == &&self_node_id)
+            .map(|(&bucket, _)| bucket)
+            .collect();
+        info!("Node {self_node_id} will manage these buckets: {buckets_to_manage:?}");
+        let cluster = cluster.write().unwrap();
+        let previous_assignments = cluster.get_bucket_node_assignments();
+        if let Err(e) = cluster.update_cluster_state(nodes_to_addrs, buckets_to_nodes, buckets_to_replicas) {
+            warn!("Joining without a connection to every node: {e}");
+        }
+        // keys of the buckets are still on their previous owners, until they hand them over
+        cluster.track_bucket_moves(&previous_assignments);
+        drop(cluster);
+
+        // nobody hands buckets over to this node before leader gets this
+        match request(&mut leader, &ClusterStateApplied { node_id: self_node_id })? {
+            CmdResponseEnum::Ok => Ok(()),
+            response => Err(unexpected_response(response)),
         }
     }
 
@@ -76,74 +219,384 @@ impl Cluster {
 
     pub fn get_node_for_key(&self, key: &Key) -> NodeId {
         let bucket = self.get_bucket_for_key(key);
+        if self.outgoing_buckets.lock().unwrap().contains_key(&bucket) {
+            return self.self_node_id.clone();
+        }
+        if let Some(previous_owner) = self.incoming_buckets.lock().unwrap().get(&bucket) {
+            return previous_owner.clone();
+        }
         self.bucket_node_assignments.lock().unwrap().get(&bucket).unwrap().clone()
     }
 
-    pub fn add_node_connection(&mut self, node_id: NodeId, connection: TcpStream) {
-        self.node_connections.lock().unwrap().insert(node_id, Arc::new(Mutex::new(connection)));
+    pub fn get_bucket_for_key(&self, key: &Key) -> BucketId {
+        calculate_hash(key) % self.num_buckets
+    }
+
+    pub fn get_replicas_for_key(&self, key: &Key) -> Vec<NodeId> {
+        let bucket = self.get_bucket_for_key(key);
+        self.get_bucket_replicas(bucket)
+    }
+
+    pub fn get_bucket_replicas(&self, bucket_id: BucketId) -> Vec<NodeId> {
+        self.bucket_replica_assignments.lock().unwrap().get(&bucket_id).cloned().unwrap_or_default()
+    }
+
+    pub fn is_bucket_primary(&self, bucket_id: BucketId) -> bool {
+        self.bucket_node_assignments.lock().unwrap().get(&bucket_id) == Some(&self.self_node_id)
+    }
+
+    /// Reads can be served locally either by primary, or by replica if reading from replicas is allowed.
+    pub fn can_read_locally(&self, key: &Key) -> bool {
+        self.is_key_local(key) || (self.replication.read_from_replica && self.get_replicas_for_key(key).contains(&self.self_node_id))
+    }
+
+    /// Serializes writes to the key's bucket, until the returned guard is dropped.
+    pub fn lock_bucket_writes(&self, key: &Key) -> MutexGuard<'_, ()> {
+        self.bucket_write_locks[self.get_bucket_for_key(key) as usize].lock().unwrap()
+    }
+
+    /// Forwards write that was applied on primary to all replicas of the key's bucket.
+    /// Replication is asynchronous, primary doesn't wait for replicas to apply it.
+    pub fn replicate_request(&self, key: &Key, request: &RequestsEnum) {
+        let bucket_id = self.get_bucket_for_key(key);
+        let replicas = self.get_bucket_replicas(bucket_id);
+        if replicas.is_empty() {
+            return;
+        }
+        let offset = {
+            let mut offsets = self.replication_offsets.lock().unwrap();
+            let offset = offsets.entry(bucket_id).or_insert(0);
+            *offset += 1;
+            *offset
+        };
+        let command = CommandsEnum::Replicate { bucket_id, offset, request: request.clone() };
+        for replica in replicas {
+            self.send_command_to_node(&replica, &command);
+        }
+    }
+
+    pub fn get_replication_offset(&self, bucket_id: BucketId) -> u64 {
+        self.replication_offsets.lock().unwrap().get(&bucket_id).cloned().unwrap_or(0)
+    }
+
+    pub fn get_replication_offsets(&self) -> HashMap<BucketId, u64> {
+        self.replication_offsets.lock().unwrap().clone()
+    }
+
+    pub fn record_replication_offset(&self, bucket_id: BucketId, offset: u64) {
+        let mut offsets = self.replication_offsets.lock().unwrap();
+        let bucket_offset = offsets.entry(bucket_id).or_insert(0);
+        *bucket_offset = (*bucket_offset).max(offset);
+    }
+
+    pub fn add_node_connection(&self, node_id: NodeId, addrs: NodeAddrs) -> io::Result<()> {
+        let connection = connect_to_node(addrs.server)?;
+        self.forwarding_connections.add_node(node_id.clone(), addrs.server);
+        self.watch_node(&node_id);
+        self.node_addrs.lock().unwrap().insert(node_id.clone(), addrs);
+        self.node_connections.lock().unwrap().insert(node_id, Arc::new(Mutex::new(Some(connection))));
+        Ok(())
+    }
+
+    // node added to the cluster is considered seen, so it is detected as failed even if it never sends a heartbeat.
+    // Known nodes keep their last heartbeat, as cluster state is published again and again
+    fn watch_node(&self, node_id: &NodeId) {
+        self.node_last_seen.lock().unwrap().entry(node_id.clone()).or_insert_with(Instant::now);
     }
 
     pub fn get_bucket_node_assignments(&self) -> HashMap<BucketId, NodeId> {
         self.bucket_node_assignments.lock().unwrap().clone()
     }
 
-    pub fn get_cluster_node_ips(&self) -> HashMap<NodeId, SocketAddr> {
-        self.node_connections.lock().unwrap().iter().map(|(node_id, arc_stream)| {
-            let stream = arc_stream.lock().unwrap();
-            let socket_addr = stream.peer_addr().unwrap();
-            (node_id.to_string(), socket_addr)
-        }).collect()
+    pub fn get_bucket_replica_assignments(&self) -> HashMap<BucketId, Vec<NodeId>> {
+        self.bucket_replica_assignments.lock().unwrap().clone()
+    }
+
+    pub fn get_cluster_node_addrs(&self) -> HashMap<NodeId, NodeAddrs> {
+        self.node_addrs.lock().unwrap().clone()
     }
 
     pub fn notify_cluster_nodes(&self, command: CommandsEnum) {
-        for (node_id, arc_stream) in self.node_connections.lock().unwrap().iter() {
+        let nodes: Vec<NodeId> = self.node_connections.lock().unwrap().keys().cloned().collect();
+        for node_id in nodes {
             info!("Notifying {node_id}");
-            let stream = arc_stream.lock().unwrap().try_clone().unwrap();
-            let mut writer = BufWriter::new(stream);
-            let mut command_str = serde_json::to_string(&command).unwrap();
-            command_str.push('\n');
-            writer.write_all(command_str.as_bytes()).unwrap();
-            writer.flush().unwrap();
+            self.send_command_to_node(&node_id, &command);
         }
     }
 
+    /// Sends client request to the node responsible for its key, and waits for the response.
+    pub fn forward_request(&self, node_id: &NodeId, request: RequestsEnum) -> io::Result<ReqResponseEnum> {
+        let request_id = self.next_forwarded_request_id.fetch_add(1, Ordering::Relaxed);
+        let command = CommandsEnum::ForwardedRequest { request_id, request };
+        match self.forwarding_connections.send_request(node_id, &command)? {
+            CmdResponseEnum::ForwardedResponse { request_id: response_id, response } if response_id == request_id => Ok(response),
+            response => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected response to request {request_id}: {response:?}"))),
+        }
+    }
+
+    pub fn send_command_to_node(&self, node_id: &NodeId, command: &CommandsEnum) -> bool {
+        let Some(arc_stream) = self.get_node_connection(node_id) else {
+            warn!("No connection to node {node_id}");
+            return false;
+        };
+        // frame is written while holding the connection, so frames sent from different threads don't interleave
+        let mut connection = arc_stream.lock().unwrap();
+        let result = self.reconnect(node_id, &mut connection).and_then(|stream| framing::write_frame(stream, command));
+        // broken connection is dropped, the next command opens a new one
+        if result.is_err() {
+            *connection = None;
+        }
+        drop(connection);
+        match result {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Couldn't send command to node {node_id}: {e}");
+                self.mark_node_disconnected(node_id);
+                false
+            }
+        }
+    }
+
+    // opens connection to the node again, if it was lost or couldn't be opened when the node was added
+    fn reconnect<'a>(&self, node_id: &NodeId, connection: &'a mut Option<TcpStream>) -> io::Result<&'a mut TcpStream> {
+        let stream = match connection.take() {
+            Some(stream) => stream,
+            None => {
+                let addrs = self.node_addrs.lock().unwrap().get(node_id).copied().ok_or(io::ErrorKind::NotFound)?;
+                connect_to_node(addrs.server)?
+            }
+        };
+        Ok(connection.insert(stream))
+    }
+
+    /// Compares bucket assignments before and after cluster change, and returns buckets this node has to hand over.
+    /// Until hand-over completes, the old owner keeps serving the bucket, and the new owner forwards requests to it.
+    pub fn track_bucket_moves(&self, previous_assignments: &HashMap<BucketId, NodeId>) -> Vec<(BucketId, NodeId)> {
+        let assignments = self.bucket_node_assignments.lock().unwrap();
+        let mut outgoing = self.outgoing_buckets.lock().unwrap();
+        let mut incoming = self.incoming_buckets.lock().unwrap();
+        let mut handoffs = Vec::new();
+        for (bucket, new_owner) in assignments.iter() {
+            match previous_assignments.get(bucket) {
+                Some(previous_owner) if previous_owner == new_owner => {}
+                Some(previous_owner) if previous_owner == &self.self_node_id => {
+                    outgoing.insert(*bucket, new_owner.clone());
+                    handoffs.push((*bucket, new_owner.clone()));
+                }
+                // if previous owner already left the cluster, it has handed its keys over before leaving
+                Some(previous_owner) if new_owner == &self.self_node_id && self.node_connections.lock().unwrap().contains_key(previous_owner) => {
+                    incoming.insert(*bucket, previous_owner.clone());
+                }
+                _ => {}
+            }
+        }
+        handoffs
+    }
+
+    /// Returns buckets this node still has to hand over, with their new owners.
+    pub fn get_outgoing_buckets(&self) -> Vec<(BucketId, NodeId)> {
+        self.outgoing_buckets.lock().unwrap().iter()
+            .map(|(bucket, node)| (*bucket, node.clone()))
+            .collect()
+    }
+
+    /// Returns replicas which were added to buckets this node is primary for. They need a full copy of the bucket.
+    pub fn get_new_replicas(&self, previous_replicas: &HashMap<BucketId, Vec<NodeId>>) -> Vec<(BucketId, NodeId)> {
+        let assignments = self.bucket_node_assignments.lock().unwrap();
+        let replicas = self.bucket_replica_assignments.lock().unwrap();
+        let mut new_replicas = Vec::new();
+        for (bucket, primary) in assignments.iter() {
+            if primary != &self.self_node_id {
+                continue;
+            }
+            let previous_bucket_replicas = previous_replicas.get(bucket).cloned().unwrap_or_default();
+            for replica in replicas.get(bucket).into_iter().flatten() {
+                if !previous_bucket_replicas.contains(replica) {
+                    new_replicas.push((*bucket, replica.clone()));
+                }
+            }
+        }
+        new_replicas
+    }
+
+    /// Returns buckets this node was a replica of, and doesn't keep anymore.
+    pub fn get_dropped_replica_buckets(&self, previous_replicas: &HashMap<BucketId, Vec<NodeId>>) -> Vec<BucketId> {
+        let assignments = self.bucket_node_assignments.lock().unwrap();
+        let replicas = self.bucket_replica_assignments.lock().unwrap();
+        previous_replicas.iter()
+            .filter(|(_, previous_bucket_replicas)| previous_bucket_replicas.contains(&self.self_node_id))
+            .filter(|(bucket, _)| assignments.get(bucket) != Some(&self.self_node_id))
+            .filter(|(bucket, _)| !replicas.get(bucket).is_some_and(|bucket_replicas| bucket_replicas.contains(&self.self_node_id)))
+            .map(|(bucket, _)| *bucket)
+            .collect()
+    }
+
+    pub fn complete_outgoing_migration(&self, bucket_id: BucketId) {
+        self.outgoing_buckets.lock().unwrap().remove(&bucket_id);
+    }
+
+    pub fn complete_incoming_migration(&self, bucket_id: BucketId) {
+        self.incoming_buckets.lock().unwrap().remove(&bucket_id);
+    }
+
     pub fn redistribute_buckets(&self) {
         let mut nodes: Vec<NodeId> = self.node_connections.lock().unwrap().keys().cloned().collect();
         nodes.push(self.self_node_id.to_string());
+        self.assign_buckets(nodes);
+    }
+
+    /// Reassigns all buckets of the cluster to other nodes, used when this node leaves.
+    pub fn release_buckets(&self) {
+        let nodes: Vec<NodeId> = self.node_connections.lock().unwrap().keys().cloned().collect();
+        self.assign_buckets(nodes);
+    }
+
+    pub fn remove_node(&self, node_id: &NodeId) {
+        self.node_connections.lock().unwrap().remove(node_id);
+        self.node_addrs.lock().unwrap().remove(node_id);
+        self.forwarding_connections.remove_node(node_id);
+        self.node_last_seen.lock().unwrap().remove(node_id);
+        self.suspected_nodes.lock().unwrap().remove(node_id);
+        self.disconnected_nodes.lock().unwrap().remove(node_id);
+        self.node_replication_offsets.lock().unwrap().remove(node_id);
+    }
+
+    pub fn get_node_ids(&self) -> Vec<NodeId> {
+        self.node_connections.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Suspects the node right away instead of waiting for heartbeats to stop.
+    /// Node is still removed only after failure timeout, so it has time to reconnect.
+    pub fn mark_node_disconnected(&self, node_id: &NodeId) {
+        // node which left the cluster closes its connections too
+        if !self.node_connections.lock().unwrap().contains_key(node_id) {
+            return;
+        }
+        if self.disconnected_nodes.lock().unwrap().insert(node_id.clone()) {
+            warn!("Lost connection to {node_id}, suspecting it is down");
+            self.suspected_nodes.lock().unwrap().insert(node_id.clone());
+        }
+    }
+
+    pub fn record_heartbeat(&self, node_id: &NodeId, replication_offsets: HashMap<BucketId, u64>) {
+        if self.disconnected_nodes.lock().unwrap().remove(node_id) {
+            info!("Node {node_id} is connected again");
+        }
+        self.node_last_seen.lock().unwrap().insert(node_id.clone(), Instant::now());
+        self.node_replication_offsets.lock().unwrap().insert(node_id.clone(), replication_offsets);
+    }
+
+    /// Makes the most up-to-date live replica the new primary of each bucket owned by nodes which are gone,
+    /// and fills up replica sets which lost their members. How up-to-date replicas are is known from their heartbeats.
+    /// Buckets without live replicas are assigned to live nodes empty. Any node which isn't connected anymore counts
+    /// as failed, as it might have been removed before this node became coordinator.
+    pub fn promote_replicas(&self) {
+        let mut live_nodes = self.get_node_ids();
+        live_nodes.push(self.self_node_id.clone());
+        live_nodes.sort();
+        let replicas_per_bucket = self.replication.replicas.min(live_nodes.len() - 1);
+        let own_offsets = self.get_replication_offsets();
+        let node_offsets = self.node_replication_offsets.lock().unwrap();
+        let offset_of = |node_id: &NodeId, bucket_id: &BucketId| -> u64 {
+            let offsets = if node_id == &self.self_node_id { Some(&own_offsets) } else { node_offsets.get(node_id) };
+            offsets.and_then(|offsets| offsets.get(bucket_id)).cloned().unwrap_or(0)
+        };
+
+        let mut assignments = self.bucket_node_assignments.lock().unwrap();
+        let mut replicas = self.bucket_replica_assignments.lock().unwrap();
+        for (bucket_id, primary) in assignments.iter_mut() {
+            let bucket_replicas = replicas.entry(*bucket_id).or_default();
+            bucket_replicas.retain(|replica| live_nodes.contains(replica));
+            if !live_nodes.contains(primary) {
+                let promoted = bucket_replicas.iter().max_by_key(|replica| offset_of(replica, bucket_id)).cloned();
+                match promoted {
+                    Some(promoted) => {
+                        info!("Promoting {promoted} to primary of bucket {bucket_id} instead of failed {primary}");
+                        bucket_replicas.retain(|replica| replica != &promoted);
+                        *primary = promoted;
+                    }
+                    None => {
+                        let new_primary = live_nodes[*bucket_id as usize % live_nodes.len()].clone();
+                        warn!("Bucket {bucket_id} has no live replicas, its keys are lost. Assigning it to {new_primary}");
+                        *primary = new_primary;
+                    }
+                }
+            }
+            // replacing lost replicas with nodes following the primary
+            let primary_index = live_nodes.iter().position(|node_id| node_id == primary)
+                .unwrap_or(*bucket_id as usize % live_nodes.len());
+            for offset in 1..live_nodes.len() {
+                if bucket_replicas.len() >= replicas_per_bucket {
+                    break;
+                }
+                let candidate = &live_nodes[(primary_index + offset) % live_nodes.len()];
+                if !bucket_replicas.contains(candidate) {
+                    bucket_replicas.push(candidate.clone());
+                }
+            }
+        }
+    }
+
+    /// Checks when each node was heard from last time, and returns nodes which are silent longer than failure timeout.
+    /// Node is monitored from the moment it is added to the cluster, see `watch_node`, so it fails even if it never sends a heartbeat.
+    pub fn find_failed_nodes(&self, suspicion_timeout: Duration, failure_timeout: Duration) -> Vec<NodeId> {
+        let now = Instant::now();
+        let nodes = self.get_node_ids();
+        let last_seen = self.node_last_seen.lock().unwrap();
+        let mut suspected = self.suspected_nodes.lock().unwrap();
+        let disconnected = self.disconnected_nodes.lock().unwrap();
+        let mut failed_nodes = Vec::new();
+        for node_id in nodes {
+            let Some(node_last_seen) = last_seen.get(&node_id) else {
+                continue;
+            };
+            let silence = now.duration_since(*node_last_seen);
+            if silence >= failure_timeout {
+                error!("No heartbeats from {node_id} for {silence:?}, considering it failed");
+                failed_nodes.push(node_id);
+            } else if silence >= suspicion_timeout {
+                if suspected.insert(node_id.clone()) {
+                    warn!("No heartbeats from {node_id} for {silence:?}, suspecting it is down");
+                }
+            } else if !disconnected.contains(&node_id) && suspected.remove(&node_id) {
+                info!("Node {node_id} is sending heartbeats again");
+            }
+        }
+        failed_nodes
+    }
+
+    /// Node with the smallest id is responsible for reassigning buckets of failed nodes.
+    pub fn is_coordinator(&self) -> bool {
+        self.node_connections.lock().unwrap().keys().all(|node_id| node_id > &self.self_node_id)
+    }
+
+    fn assign_buckets(&self, mut nodes: Vec<NodeId>) {
         nodes.sort();
         let mut buckets: Vec<BucketId> = self.bucket_node_assignments.lock().unwrap().keys().cloned().collect();
         buckets.sort();
         info!("redistributing nodes: {nodes:?}, buckets: {buckets:?}");
-        let buckets_per_node = buckets.len() / nodes.len();
+        let buckets_per_node = buckets.len().div_ceil(nodes.len());
         let buckets_iter = buckets.chunks(buckets_per_node);
+        let replicas_per_bucket = self.replication.replicas.min(nodes.len().saturating_sub(1));
         self.bucket_node_assignments.lock().unwrap().clear();
-        for (node_id, buckets) in nodes.iter().zip(buckets_iter) {
+        self.bucket_replica_assignments.lock().unwrap().clear();
+        for (node_index, (node_id, buckets)) in nodes.iter().zip(buckets_iter).enumerate() {
+            // replicas of a bucket are the nodes following its primary
+            let replicas: Vec<NodeId> = (1..=replicas_per_bucket)
+                .map(|offset| nodes[(node_index + offset) % nodes.len()].clone())
+                .collect();
             for bucket in buckets {
                 self.bucket_node_assignments.lock().unwrap().insert(*bucket, node_id.clone());
+                self.bucket_replica_assignments.lock().unwrap().insert(*bucket, replicas.clone());
             }
         }
     }
 
-    pub fn get_node_connection(&self, target_node: &NodeId) -> Option<Arc<Mutex<TcpStream>>> {
+    pub fn get_node_connection(&self, target_node: &NodeId) -> Option<NodeConnection> {
         self.node_connections.lock().unwrap()
             .get(target_node).cloned()
     }
 
-    fn handle_cluster_join(self_node_id: &NodeId,
-                           leader_node: SocketAddr,
-                           bucket_node_assignments: Arc<Mutex<HashMap<BucketId, NodeId>>>,
-                           node_connections: Arc<Mutex<HashMap<NodeId, Arc<Mutex<TcpStream>>>>>,
-    ) {
-        let stream = TcpStream::connect(leader_node.to_string()).expect("Failed to connect to server");
-        let cluster_state = Self::request_cluster_state(stream.try_clone().unwrap());
-        Self::init_bucket_nodes(self_node_id, &cluster_state, bucket_node_assignments.clone(), node_connections.clone());
-        Self::join_cluster(self_node_id, stream.try_clone().unwrap(), bucket_node_assignments.clone());
-    }
-
-    fn get_bucket_for_key(&self, key: &Key) -> BucketId {
-        calculate_hash(key) % self.num_buckets
-    }
-
     fn init_self_bucket_nodes(self_id: &NodeId,
                               num_buckets: u64,
                               bucket_nodes: Arc<Mutex<HashMap<BucketId, NodeId>>>,
@@ -153,76 +606,34 @@ impl Cluster {
             buckets.insert(bucket_id, self_id.clone());
         }
     }
+}
 
-    fn init_bucket_nodes(self_id: &NodeId,
-                         cluster_state: &CmdResponseEnum,
-                         self_bucket_nodes: Arc<Mutex<HashMap<BucketId, NodeId>>>,
-                         self_node_connections: Arc<Mutex<HashMap<NodeId, Arc<Mutex<TcpStream>>>>>,
-    ) {
-        match cluster_state {
-            CmdResponseEnum::ClusterState { buckets_to_nodes, nodes_to_ips } => {
-                // opens connections to all the existing nodes
-                buckets_to_nodes.iter().for_each(|(bucket, node)| {
-                    info!("{self_id}.init_bucket_nodes: Bucket {bucket} is handled by {node}");
-                    self_bucket_nodes.lock().unwrap().insert(*bucket, node.clone());
-                });
-                nodes_to_ips.iter().for_each(|(node, ip)| {
-                    info!("{self_id}.init_bucket_nodes: Node {node} has ip: {ip}");
-                    let connection = TcpStream::connect(ip).expect("Failed to connect to node");
-                    self_node_connections.lock().unwrap().insert(node.clone(), Arc::new(Mutex::new(connection)));
-                });
-            }
-            _ => error!("")
+// Sends command to the leader and waits for its response.
+// Connection is used only by the joining node, so nothing else is written to it in the meantime.
+fn request(leader: &mut TcpStream, command: &CommandsEnum) -> io::Result<CmdResponseEnum> {
+    framing::write_frame(leader, command)?;
+    let payload = framing::read_frame(leader)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+    rmp_serde::from_slice(&payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn request_cluster_state(leader: &mut TcpStream, command: &CommandsEnum) -> io::Result<ClusterState> {
+    match request(leader, command)? {
+        CmdResponseEnum::ClusterState { nodes_to_addrs, buckets_to_nodes, buckets_to_replicas } => {
+            Ok((nodes_to_addrs, buckets_to_nodes, buckets_to_replicas))
         }
+        response => Err(unexpected_response(response)),
     }
+}
 
-    fn request_cluster_state(stream: TcpStream) -> CmdResponseEnum {
-        let mut reader = BufReader::new(stream.try_clone().unwrap());
-        let mut writer = BufWriter::new(stream.try_clone().unwrap());
-        let command = GetClusterState {};
-        let mut command_str = serde_json::to_string(&command).unwrap();
-        command_str.push('\n');
-        writer.write_all(command_str.as_bytes()).unwrap();
-        writer.flush().unwrap();
-
-        let mut s = String::new();
-        reader.read_line(&mut s).unwrap();
-        info!("Received cluster state: {s}");
-        serde_json::from_str(&s).unwrap()
-    }
+fn unexpected_response(response: CmdResponseEnum) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("unexpected response from leader: {response:?}"))
+}
 
-    fn join_cluster(self_node_id: &NodeId,
-                    stream: TcpStream,
-                    bucket_nodes: Arc<Mutex<HashMap<BucketId, NodeId>>>,
-    ) {
-        let mut writer = BufWriter::new(stream.try_clone().unwrap());
-        let command = JoinCluster { node_id: self_node_id.to_string() };
-        let mut command_str = serde_json::to_string(&command).unwrap();
-        command_str.push('\n');
-        writer.write_all(command_str.as_bytes()).unwrap();
-        writer.flush().unwrap();
-
-        let mut reader = BufReader::new(stream.try_clone().unwrap());
-        let mut s = String::new();
-        reader.read_line(&mut s).unwrap();
-        info!("Received join cluster response: {s}");
-        match serde_json::from_str(&s).unwrap() {
-            UpdateClusterState { nodes_to_ips, buckets_to_nodes } => {
-                let buckets_to_manage: Vec<BucketId> = buckets_to_nodes.iter()
-                    .filter(|(_, node_id)| { node_id == &self_node_id })
-                    .map(|(&key, _)| key)
-                    .collect();
-                info!("Node {self_node_id} will manage these buckets: {buckets_to_manage:?}");
-                let mut bucket_nodes_cur = bucket_nodes.lock().unwrap();
-                for bucket in buckets_to_manage {
-                    bucket_nodes_cur.insert(bucket, self_node_id.to_string());
-                }
-            }
-            _ => {
-                warn!("Got incorrect response")
-            }
-        }
-    }
+// other nodes are always talked to using frames
+fn connect_to_node<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr)?;
+    framing::request_handshake(&mut stream)?;
+    Ok(stream)
 }
 
 fn calculate_hash<T: Hash>(t: &T) -> u64 {
@@ -230,3 +641,48 @@ fn calculate_hash<T: Hash>(t: &T) -> u64 {
     t.hash(&mut s);
     s.finish()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster(replicas: usize) -> Cluster {
+        let addrs = NodeAddrs { server: "127.0.0.1:8001".parse().unwrap(), client: "127.0.0.1:7001".parse().unwrap() };
+        let forwarding = ForwardingConfig {
+            max_in_flight: 1,
+            connect_timeout: Duration::from_secs(1),
+            request_timeout: Duration::from_secs(1),
+        };
+        Cluster::new(4, ReplicationConfig { replicas, read_from_replica: false }, forwarding, "node-a".to_string(), addrs, None)
+    }
+
+    #[test]
+    fn buckets_of_nodes_removed_earlier_are_reassigned() {
+        // node-b was removed from this node's view before it became coordinator, node-c isn't known at all
+        let cluster = cluster(1);
+        for bucket_id in 0..4 {
+            cluster.bucket_node_assignments.lock().unwrap().insert(bucket_id, "node-b".to_string());
+            cluster.bucket_replica_assignments.lock().unwrap().insert(bucket_id, vec!["node-c".to_string()]);
+        }
+        cluster.bucket_replica_assignments.lock().unwrap().insert(0, vec!["node-a".to_string()]);
+        cluster.promote_replicas();
+        for bucket_id in 0..4 {
+            assert_eq!(cluster.get_bucket_node_assignments()[&bucket_id], "node-a");
+            assert!(cluster.get_bucket_replicas(bucket_id).is_empty());
+        }
+    }
+
+    #[test]
+    fn unreachable_node_in_cluster_state_is_suspected() {
+        let cluster = cluster(0);
+        // nothing listens on the discard port
+        let addr: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let nodes_to_addrs = HashMap::from([("node-b".to_string(), NodeAddrs { server: addr, client: addr })]);
+        let buckets_to_nodes = (0..4).map(|bucket_id| (bucket_id, "node-b".to_string())).collect();
+        assert!(cluster.update_cluster_state(nodes_to_addrs, buckets_to_nodes, HashMap::new()).is_err());
+        assert_eq!(cluster.get_node_ids(), ["node-b"]);
+        assert!(cluster.suspected_nodes.lock().unwrap().contains("node-b"));
+        assert_eq!(cluster.get_node_for_key(&"key".to_string()), "node-b");
+        assert!(!cluster.send_command_to_node(&"node-b".to_string(), &GetClusterState {}));
+    }
+}
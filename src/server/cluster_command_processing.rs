@@ -1,40 +1,199 @@
-use std::net::TcpStream;
-use log::warn;
-use crate::server::cluster::Cluster;
+use std::collections::HashMap;
+use log::{error, info, warn};
+use crate::server::cache::Cache;
+use crate::server::cluster::{BucketId, Cluster, NodeId};
 use crate::server::commands::{CmdResponseEnum, CommandsEnum};
+use crate::server::user_request_processing;
 
-pub fn process_cluster_command(command: CommandsEnum, 
-                               cluster: &mut Cluster, 
-                               connection_stream: TcpStream
+const MIGRATION_BATCH_SIZE: usize = 100;
+
+pub fn process_cluster_command(command: CommandsEnum,
+                               cluster: &Cluster,
+                               cache: &Cache,
 ) -> CmdResponseEnum {
     match command {
-        CommandsEnum::JoinCluster { node_id: new_node_id } => {
-            cluster.add_node_connection(new_node_id.clone(), connection_stream.try_clone().unwrap());
-            let mut nodes_to_ips = cluster.get_cluster_node_ips();
-            nodes_to_ips.insert(cluster.self_node_id.to_string(), connection_stream.local_addr().unwrap());
+        CommandsEnum::JoinCluster { node_id: new_node_id, addrs } => {
+            if let Err(e) = cluster.add_node_connection(new_node_id.clone(), addrs) {
+                error!("Couldn't connect back to joining node {new_node_id} at {}: {e}", addrs.server);
+                return CmdResponseEnum::ErrorProcessingCommand;
+            }
+            let mut nodes_to_addrs = cluster.get_cluster_node_addrs();
+            nodes_to_addrs.insert(cluster.self_node_id.to_string(), cluster.self_addrs);
+            let previous_assignments = cluster.get_bucket_node_assignments();
+            let previous_replicas = cluster.get_bucket_replica_assignments();
             cluster.redistribute_buckets();
+            // buckets are handed over once the joining node has applied the new state, see `ClusterStateApplied`,
+            // otherwise their migration could complete before it knows they are incoming. Until then, they are served here
+            cluster.track_bucket_moves(&previous_assignments);
+            rebalance_replicas(&previous_replicas, cluster, cache);
             let buckets_to_nodes = cluster.get_bucket_node_assignments();
-
-            let update_cluster_cmd = CommandsEnum::UpdateClusterState {
-                nodes_to_ips: nodes_to_ips.clone(),
-                buckets_to_nodes: buckets_to_nodes.clone(),
-            };
-            cluster.notify_cluster_nodes(update_cluster_cmd);
-            CmdResponseEnum::ClusterState { nodes_to_ips, buckets_to_nodes }
+            let buckets_to_replicas = cluster.get_bucket_replica_assignments();
+            CmdResponseEnum::ClusterState { nodes_to_addrs, buckets_to_nodes, buckets_to_replicas }
+        }
+        CommandsEnum::ClusterStateApplied { node_id } => {
+            info!("Node {node_id} has joined, publishing new cluster state");
+            let mut nodes_to_addrs = cluster.get_cluster_node_addrs();
+            nodes_to_addrs.insert(cluster.self_node_id.to_string(), cluster.self_addrs);
+            let buckets_to_nodes = cluster.get_bucket_node_assignments();
+            let buckets_to_replicas = cluster.get_bucket_replica_assignments();
+            cluster.notify_cluster_nodes(CommandsEnum::UpdateClusterState { nodes_to_addrs, buckets_to_nodes, buckets_to_replicas });
+            hand_off_buckets(cluster.get_outgoing_buckets(), cluster, cache);
+            CmdResponseEnum::Ok
         }
         CommandsEnum::GetClusterState {} => {
-            let nodes_to_ips = cluster.get_cluster_node_ips();
+            let mut nodes_to_addrs = cluster.get_cluster_node_addrs();
+            nodes_to_addrs.insert(cluster.self_node_id.to_string(), cluster.self_addrs);
             let buckets_to_nodes = cluster.get_bucket_node_assignments();
-            CmdResponseEnum::ClusterState { nodes_to_ips, buckets_to_nodes }
+            let buckets_to_replicas = cluster.get_bucket_replica_assignments();
+            CmdResponseEnum::ClusterState { nodes_to_addrs, buckets_to_nodes, buckets_to_replicas }
         }
-        CommandsEnum::UpdateClusterState { nodes_to_ips, buckets_to_nodes } => {
-            cluster.update_cluster_state(nodes_to_ips, buckets_to_nodes);
+        CommandsEnum::UpdateClusterState { nodes_to_addrs, buckets_to_nodes, buckets_to_replicas } => {
+            let previous_assignments = cluster.get_bucket_node_assignments();
+            let previous_replicas = cluster.get_bucket_replica_assignments();
+            if let Err(e) = cluster.update_cluster_state(nodes_to_addrs, buckets_to_nodes, buckets_to_replicas) {
+                warn!("Applied cluster state without a connection to every node: {e}");
+            }
+            rebalance_buckets(&previous_assignments, &previous_replicas, cluster, cache);
             CmdResponseEnum::Ok
         }
         CommandsEnum::LeaveCluster { node_id } => {
             warn!("Node {node_id} leaves the cluster");
-            // TODO: remove node from node_connections, close connection
+            cluster.remove_node(&node_id);
+            CmdResponseEnum::Ok
+        }
+        CommandsEnum::MigrateBucketEntries { bucket_id, offset, entries } => {
+            info!("Received {} keys of bucket {bucket_id}", entries.len());
+            for entry in &entries {
+                cache.put_entry(entry.clone());
+            }
+            cluster.record_replication_offset(bucket_id, offset);
+            // keys handed over to the new primary should reach its replicas as well
+            if cluster.is_bucket_primary(bucket_id) {
+                let command = CommandsEnum::MigrateBucketEntries { bucket_id, offset, entries };
+                for replica in cluster.get_bucket_replicas(bucket_id) {
+                    cluster.send_command_to_node(&replica, &command);
+                }
+            }
+            CmdResponseEnum::Ok
+        }
+        CommandsEnum::BucketMigrationCompleted { bucket_id } => {
+            info!("Bucket {bucket_id} is fully migrated to this node");
+            cluster.complete_incoming_migration(bucket_id);
+            CmdResponseEnum::Ok
+        }
+        CommandsEnum::Heartbeat { node_id, replication_offsets } => {
+            cluster.record_heartbeat(&node_id, replication_offsets);
+            CmdResponseEnum::Ok
+        }
+        CommandsEnum::ForwardedRequest { request_id, request } => {
+            let response = user_request_processing::process_forwarded_request(request, cache, cluster);
+            CmdResponseEnum::ForwardedResponse { request_id, response }
+        }
+        CommandsEnum::Replicate { bucket_id, offset, request } => {
+            user_request_processing::execute_request(request, cache);
+            cluster.record_replication_offset(bucket_id, offset);
             CmdResponseEnum::Ok
         }
     }
-}
\ No newline at end of file
+}
+
+/// Drops failed nodes from the cluster. Coordinator node also promotes replicas of their buckets
+/// to primaries and publishes new cluster state, others wait for that update.
+pub fn remove_failed_nodes(failed_nodes: Vec<NodeId>, cluster: &Cluster, cache: &Cache) {
+    for node_id in &failed_nodes {
+        cluster.remove_node(node_id);
+    }
+    if !cluster.is_coordinator() {
+        return;
+    }
+    warn!("Reassigning buckets of failed nodes {failed_nodes:?}");
+    let previous_assignments = cluster.get_bucket_node_assignments();
+    let previous_replicas = cluster.get_bucket_replica_assignments();
+    cluster.promote_replicas();
+    let mut nodes_to_addrs = cluster.get_cluster_node_addrs();
+    nodes_to_addrs.insert(cluster.self_node_id.to_string(), cluster.self_addrs);
+    let buckets_to_nodes = cluster.get_bucket_node_assignments();
+    let buckets_to_replicas = cluster.get_bucket_replica_assignments();
+    cluster.notify_cluster_nodes(CommandsEnum::UpdateClusterState { nodes_to_addrs, buckets_to_nodes, buckets_to_replicas });
+    rebalance_buckets(&previous_assignments, &previous_replicas, cluster, cache);
+}
+
+/// Gracefully drains this node: hands all its buckets with their keys over to the remaining nodes,
+/// publishes new cluster state without this node and tells peers to drop their connections to it.
+/// Node should shut down after this returns.
+pub fn leave_cluster(cluster: &Cluster, cache: &Cache) {
+    let self_node_id = cluster.self_node_id.clone();
+    let nodes_to_addrs = cluster.get_cluster_node_addrs();
+    if nodes_to_addrs.is_empty() {
+        warn!("Node {self_node_id} is the only node in the cluster, its keys are lost");
+        return;
+    }
+    info!("Node {self_node_id} is leaving the cluster");
+    let previous_assignments = cluster.get_bucket_node_assignments();
+    cluster.release_buckets();
+    let handoffs = cluster.track_bucket_moves(&previous_assignments);
+    // keys are handed over before new state is published, so new owners never miss them
+    hand_off_buckets(handoffs, cluster, cache);
+
+    let buckets_to_nodes = cluster.get_bucket_node_assignments();
+    let buckets_to_replicas = cluster.get_bucket_replica_assignments();
+    cluster.notify_cluster_nodes(CommandsEnum::UpdateClusterState { nodes_to_addrs, buckets_to_nodes, buckets_to_replicas });
+    cluster.notify_cluster_nodes(CommandsEnum::LeaveCluster { node_id: self_node_id });
+}
+
+// moves keys around after bucket assignments have changed: hands buckets over to their new primaries,
+// sends full copies of buckets to their new replicas, and drops copies this node doesn't need to keep anymore
+fn rebalance_buckets(previous_assignments: &HashMap<BucketId, NodeId>,
+                     previous_replicas: &HashMap<BucketId, Vec<NodeId>>,
+                     cluster: &Cluster,
+                     cache: &Cache,
+) {
+    let handoffs = cluster.track_bucket_moves(previous_assignments);
+    hand_off_buckets(handoffs, cluster, cache);
+    rebalance_replicas(previous_replicas, cluster, cache);
+}
+
+fn rebalance_replicas(previous_replicas: &HashMap<BucketId, Vec<NodeId>>, cluster: &Cluster, cache: &Cache) {
+    for (bucket_id, replica) in cluster.get_new_replicas(previous_replicas) {
+        let entries = cache.get_entries(|key| cluster.get_bucket_for_key(key) == bucket_id);
+        info!("Copying bucket {bucket_id} with {} keys to its new replica {replica}", entries.len());
+        let offset = cluster.get_replication_offset(bucket_id);
+        for batch in entries.chunks(MIGRATION_BATCH_SIZE) {
+            let command = CommandsEnum::MigrateBucketEntries { bucket_id, offset, entries: batch.to_vec() };
+            cluster.send_command_to_node(&replica, &command);
+        }
+    }
+
+    for bucket_id in cluster.get_dropped_replica_buckets(previous_replicas) {
+        info!("Node is not a replica of bucket {bucket_id} anymore, dropping its keys");
+        for entry in cache.get_entries(|key| cluster.get_bucket_for_key(key) == bucket_id) {
+            cache.remove(&entry.key);
+        }
+    }
+}
+
+// streams keys of each bucket to its new owner, then drops them locally.
+// cluster state is locked exclusively for the whole hand-over, so no client writes to the bucket are lost in between
+fn hand_off_buckets(handoffs: Vec<(BucketId, NodeId)>, cluster: &Cluster, cache: &Cache) {
+    for (bucket_id, target_node) in handoffs {
+        let entries = cache.get_entries(|key| cluster.get_bucket_for_key(key) == bucket_id);
+        info!("Handing over bucket {bucket_id} with {} keys to {target_node}", entries.len());
+        let offset = cluster.get_replication_offset(bucket_id);
+        let entries_sent = entries.chunks(MIGRATION_BATCH_SIZE).all(|batch| {
+            let command = CommandsEnum::MigrateBucketEntries { bucket_id, offset, entries: batch.to_vec() };
+            cluster.send_command_to_node(&target_node, &command)
+        });
+        let completed = entries_sent && cluster.send_command_to_node(&target_node, &CommandsEnum::BucketMigrationCompleted { bucket_id });
+        if !completed {
+            warn!("Couldn't hand over bucket {bucket_id} to {target_node}, keep serving it");
+            continue;
+        }
+        // previous primary can stay a replica of the bucket, then it keeps its copy
+        if !cluster.get_bucket_replicas(bucket_id).contains(&cluster.self_node_id) {
+            for entry in entries {
+                cache.remove(&entry.key);
+            }
+        }
+        cluster.complete_outgoing_migration(bucket_id);
+    }
+}
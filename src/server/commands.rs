@@ -1,33 +1,85 @@
 use std::collections::HashMap;
-use std::net::{SocketAddr};
 use serde::{Deserialize, Serialize};
-use crate::server::cache::Key;
-use crate::server::cluster::{BucketId, NodeId};
+use crate::server::cache::{CacheEntry, Key};
+use crate::server::cluster::{BucketId, NodeAddrs, NodeId};
+use crate::server::requests::{ReqResponseEnum, RequestsEnum};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum CommandsEnum {
     JoinCluster {
         node_id: NodeId,
+        // leader connects back to the cluster port of the joining node, other nodes learn its addresses from leader
+        addrs: NodeAddrs,
+    },
+    // joining node has applied the state leader answered `JoinCluster` with, so its buckets can be handed over to it
+    ClusterStateApplied {
+        node_id: NodeId,
     },
     LeaveCluster {
         node_id: NodeId,
     },
     GetClusterState {},
     UpdateClusterState {
-        nodes_to_ips: HashMap<NodeId, SocketAddr>,
+        nodes_to_addrs: HashMap<NodeId, NodeAddrs>,
         buckets_to_nodes: HashMap<BucketId, NodeId>,
+        buckets_to_replicas: HashMap<BucketId, Vec<NodeId>>,
+    },
+    MigrateBucketEntries {
+        bucket_id: BucketId,
+        // replication offset of the bucket on the sender
+        offset: u64,
+        entries: Vec<CacheEntry>,
+    },
+    BucketMigrationCompleted {
+        bucket_id: BucketId,
+    },
+    Heartbeat {
+        node_id: NodeId,
+        replication_offsets: HashMap<BucketId, u64>,
     },
+    // write applied on bucket primary, which replica should apply as well
+    Replicate {
+        bucket_id: BucketId,
+        offset: u64,
+        request: RequestsEnum,
+    },
+    // client request sent to the node responsible for its key, answered with `ForwardedResponse` with the same id
+    ForwardedRequest {
+        request_id: u64,
+        request: RequestsEnum,
+    },
+}
+
+impl CommandsEnum {
+    /// One-way commands are sent without waiting for response, so none is written back.
+    /// Everything sent with `Cluster::send_command_to_node` is one-way, as it never reads from the connection.
+    pub fn is_one_way(&self) -> bool {
+        !matches!(self, CommandsEnum::JoinCluster { .. }
+            | CommandsEnum::ClusterStateApplied { .. }
+            | CommandsEnum::GetClusterState {}
+            | CommandsEnum::ForwardedRequest { .. })
+    }
+
+    /// Commands which don't change cluster state are processed in parallel with client requests.
+    pub fn changes_cluster_state(&self) -> bool {
+        !matches!(self, CommandsEnum::Heartbeat { .. } | CommandsEnum::Replicate { .. } | CommandsEnum::ForwardedRequest { .. })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum CmdResponseEnum {
     Ok,
     ClusterState {
-        nodes_to_ips: HashMap<NodeId, SocketAddr>,
+        nodes_to_addrs: HashMap<NodeId, NodeAddrs>,
         buckets_to_nodes: HashMap<BucketId, NodeId>,
+        buckets_to_replicas: HashMap<BucketId, Vec<NodeId>>,
     },
     KeysList {
         keys: Vec<Key>,
     },
+    ForwardedResponse {
+        request_id: u64,
+        response: ReqResponseEnum,
+    },
     ErrorProcessingCommand,
 }
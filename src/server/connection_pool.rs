@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::server::cluster::NodeId;
+use crate::server::framing;
+
+#[derive(Debug)]
+pub struct ForwardingConfig {
+    // requests forwarded to one node at the same time, each of them uses a connection of its own
+    pub max_in_flight: usize,
+    pub connect_timeout: Duration,
+    // applies to waiting for a free connection as well as to sending request and receiving response
+    pub request_timeout: Duration,
+}
+
+/// Connections used for client requests forwarded to other nodes, separate from the ones cluster commands are sent over.
+/// Connection is taken from the pool for a whole round trip, so requests to the same node don't wait for each other,
+/// and is returned to the pool once the response is received.
+pub struct ConnectionPool {
+    config: ForwardingConfig,
+    nodes: Mutex<HashMap<NodeId, Arc<NodeConnections>>>,
+}
+
+struct NodeConnections {
+    addr: SocketAddr,
+    state: Mutex<NodeConnectionsState>,
+    // notified when a request completes, so a waiting one can be sent
+    request_completed: Condvar,
+}
+
+#[derive(Default)]
+struct NodeConnectionsState {
+    idle: Vec<TcpStream>,
+    in_flight: usize,
+}
+
+impl ConnectionPool {
+    pub fn new(config: ForwardingConfig) -> ConnectionPool {
+        ConnectionPool {
+            config,
+            nodes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn add_node(&self, node_id: NodeId, addr: SocketAddr) {
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.get(&node_id).is_some_and(|node| node.addr == addr) {
+            return;
+        }
+        nodes.insert(node_id, Arc::new(NodeConnections {
+            addr,
+            state: Mutex::new(NodeConnectionsState::default()),
+            request_completed: Condvar::new(),
+        }));
+    }
+
+    // idle connections are closed right away, the ones in use are closed when their requests complete
+    pub fn remove_node(&self, node_id: &NodeId) {
+        self.nodes.lock().unwrap().remove(node_id);
+    }
+
+    pub fn retain_nodes(&self, keep: impl Fn(&NodeId) -> bool) {
+        self.nodes.lock().unwrap().retain(|node_id, _| keep(node_id));
+    }
+
+    /// Sends the request to the node and waits for its response.
+    /// Pooled connection which the node has closed in the meantime is replaced with a new one, other failures are returned.
+    pub fn send_request<T: Serialize, R: DeserializeOwned>(&self, node_id: &NodeId, request: &T) -> io::Result<R> {
+        let Some(node) = self.nodes.lock().unwrap().get(node_id).cloned() else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no connections to node {node_id}")));
+        };
+        let idle = node.acquire(self.config.max_in_flight, self.config.request_timeout)?;
+        let result = match idle {
+            Some(stream) => match round_trip(stream, request) {
+                // node closed the idle connection, e.g. because it restarted, so the request never reached it
+                Err(e) if is_closed(&e) => {
+                    info!("Pooled connection to {node_id} was closed, reconnecting");
+                    self.connect(node.addr).and_then(|stream| round_trip(stream, request))
+                }
+                result => result,
+            },
+            None => self.connect(node.addr).and_then(|stream| round_trip(stream, request)),
+        };
+        match result {
+            Ok((stream, payload)) => {
+                node.release(Some(stream));
+                rmp_serde::from_slice(&payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            // connection might still receive the response later, so it can't be reused
+            Err(e) => {
+                node.release(None);
+                Err(e)
+            }
+        }
+    }
+
+    fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect_timeout(&addr, self.config.connect_timeout)?;
+        stream.set_read_timeout(Some(self.config.request_timeout))?;
+        stream.set_write_timeout(Some(self.config.request_timeout))?;
+        framing::request_handshake(&mut stream)?;
+        Ok(stream)
+    }
+}
+
+impl NodeConnections {
+    // Waits until fewer than `max_in_flight` requests are sent to the node, returns an idle connection if there is one
+    fn acquire(&self, max_in_flight: usize, timeout: Duration) -> io::Result<Option<TcpStream>> {
+        let state = self.state.lock().unwrap();
+        let (mut state, _) = self.request_completed
+            .wait_timeout_while(state, timeout, |state| state.in_flight >= max_in_flight)
+            .unwrap();
+        if state.in_flight >= max_in_flight {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, format!("{max_in_flight} requests are already in flight")));
+        }
+        state.in_flight += 1;
+        Ok(state.idle.pop())
+    }
+
+    fn release(&self, connection: Option<TcpStream>) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        state.idle.extend(connection);
+        self.request_completed.notify_one();
+    }
+}
+
+fn round_trip<T: Serialize>(stream: TcpStream, request: &T) -> io::Result<(TcpStream, Vec<u8>)> {
+    // each connection carries one request at a time, so there is nothing to buffer
+    framing::write_frame(&mut &stream, request).map_err(to_timeout)?;
+    let payload = framing::read_frame(&mut &stream).map_err(to_timeout)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+    Ok((stream, payload))
+}
+
+// socket timeouts are reported as `WouldBlock` on unix
+fn to_timeout(e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::WouldBlock => io::Error::new(io::ErrorKind::TimedOut, "request timed out"),
+        _ => e,
+    }
+}
+
+fn is_closed(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted)
+}
@@ -0,0 +1,305 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::time::SystemTime;
+use clap::ValueEnum;
+use priority_queue::PriorityQueue;
+use rand::Rng;
+use crate::server::cache::Key;
+
+/// Decides which key is evicted when cache goes over its limits.
+/// Cache notifies the policy about every key it stores, reads or removes.
+pub trait EvictionPolicy: Send {
+    // called both for new keys and for overwrites of existing ones
+    fn on_insert(&mut self, key: &Key, expiration_time: Option<SystemTime>);
+    fn on_access(&mut self, key: &Key);
+    fn on_delete(&mut self, key: &Key);
+    // called when TTL of existing key is set or removed without changing its value
+    fn on_expiration_change(&mut self, _key: &Key, _expiration_time: Option<SystemTime>) {}
+    // returns key which should be evicted next, if there is any
+    fn pick_victim(&mut self) -> Option<Key>;
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum EvictionPolicyKind {
+    // least recently used key
+    Lru,
+    // least frequently used key, least recently used among equally used ones
+    Lfu,
+    // the oldest inserted key
+    Fifo,
+    // any key
+    Random,
+    // key which expires soonest, keys without TTL are never evicted
+    VolatileTtl,
+}
+
+impl EvictionPolicyKind {
+    pub fn create(&self) -> Box<dyn EvictionPolicy> {
+        match self {
+            EvictionPolicyKind::Lru => Box::new(LruPolicy::default()),
+            EvictionPolicyKind::Lfu => Box::new(LfuPolicy::default()),
+            EvictionPolicyKind::Fifo => Box::new(FifoPolicy::default()),
+            EvictionPolicyKind::Random => Box::new(RandomPolicy::default()),
+            EvictionPolicyKind::VolatileTtl => Box::new(VolatileTtlPolicy::default()),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct LruPolicy {
+    // key with the smallest access number is the least recently used one
+    queue: PriorityQueue<Key, Reverse<u64>>,
+    access_counter: u64,
+}
+
+impl EvictionPolicy for LruPolicy {
+    fn on_insert(&mut self, key: &Key, _expiration_time: Option<SystemTime>) {
+        self.on_access(key);
+    }
+
+    fn on_access(&mut self, key: &Key) {
+        self.access_counter += 1;
+        // pushing to the queue existing key overwrites its last access
+        self.queue.push(key.to_string(), Reverse(self.access_counter));
+    }
+
+    fn on_delete(&mut self, key: &Key) {
+        self.queue.remove(key);
+    }
+
+    fn pick_victim(&mut self) -> Option<Key> {
+        self.queue.peek().map(|(key, _)| key.clone())
+    }
+}
+
+#[derive(Default)]
+pub struct LfuPolicy {
+    // ordered by number of accesses, then by last access
+    queue: PriorityQueue<Key, Reverse<(u64, u64)>>,
+    access_counter: u64,
+}
+
+impl EvictionPolicy for LfuPolicy {
+    fn on_insert(&mut self, key: &Key, _expiration_time: Option<SystemTime>) {
+        self.on_access(key);
+    }
+
+    fn on_access(&mut self, key: &Key) {
+        self.access_counter += 1;
+        let accesses = self.queue.get_priority(key).map(|priority| priority.0.0).unwrap_or(0);
+        self.queue.push(key.to_string(), Reverse((accesses + 1, self.access_counter)));
+    }
+
+    fn on_delete(&mut self, key: &Key) {
+        self.queue.remove(key);
+    }
+
+    fn pick_victim(&mut self) -> Option<Key> {
+        self.queue.peek().map(|(key, _)| key.clone())
+    }
+}
+
+#[derive(Default)]
+pub struct FifoPolicy {
+    queue: PriorityQueue<Key, Reverse<u64>>,
+    insert_counter: u64,
+}
+
+impl EvictionPolicy for FifoPolicy {
+    fn on_insert(&mut self, key: &Key, _expiration_time: Option<SystemTime>) {
+        // overwriting a key doesn't move it to the end of the queue
+        if self.queue.get_priority(key).is_none() {
+            self.insert_counter += 1;
+            self.queue.push(key.to_string(), Reverse(self.insert_counter));
+        }
+    }
+
+    fn on_access(&mut self, _key: &Key) {}
+
+    fn on_delete(&mut self, key: &Key) {
+        self.queue.remove(key);
+    }
+
+    fn pick_victim(&mut self) -> Option<Key> {
+        self.queue.peek().map(|(key, _)| key.clone())
+    }
+}
+
+/// Set of keys which allows picking a random one in constant time.
+#[derive(Default)]
+pub struct RandomKeySet {
+    keys: Vec<Key>,
+    // position of each key in `keys`, for removal in constant time
+    positions: HashMap<Key, usize>,
+}
+
+impl RandomKeySet {
+    pub fn insert(&mut self, key: &Key) {
+        if !self.positions.contains_key(key) {
+            self.positions.insert(key.to_string(), self.keys.len());
+            self.keys.push(key.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, key: &Key) {
+        if let Some(position) = self.positions.remove(key) {
+            self.keys.swap_remove(position);
+            if let Some(moved_key) = self.keys.get(position) {
+                self.positions.insert(moved_key.clone(), position);
+            }
+        }
+    }
+
+    pub fn random_key(&self) -> Option<&Key> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let position = rand::rng().random_range(0..self.keys.len());
+        Some(&self.keys[position])
+    }
+}
+
+#[derive(Default)]
+pub struct RandomPolicy {
+    keys: RandomKeySet,
+}
+
+impl EvictionPolicy for RandomPolicy {
+    fn on_insert(&mut self, key: &Key, _expiration_time: Option<SystemTime>) {
+        self.keys.insert(key);
+    }
+
+    fn on_access(&mut self, _key: &Key) {}
+
+    fn on_delete(&mut self, key: &Key) {
+        self.keys.remove(key);
+    }
+
+    fn pick_victim(&mut self) -> Option<Key> {
+        self.keys.random_key().cloned()
+    }
+}
+
+#[derive(Default)]
+pub struct VolatileTtlPolicy {
+    queue: PriorityQueue<Key, Reverse<SystemTime>>,
+}
+
+impl EvictionPolicy for VolatileTtlPolicy {
+    fn on_insert(&mut self, key: &Key, expiration_time: Option<SystemTime>) {
+        match expiration_time {
+            Some(expiration_time) => {
+                self.queue.push(key.to_string(), Reverse(expiration_time));
+            }
+            None => {
+                self.queue.remove(key);
+            }
+        }
+    }
+
+    fn on_access(&mut self, _key: &Key) {}
+
+    fn on_delete(&mut self, key: &Key) {
+        self.queue.remove(key);
+    }
+
+    fn on_expiration_change(&mut self, key: &Key, expiration_time: Option<SystemTime>) {
+        self.on_insert(key, expiration_time);
+    }
+
+    fn pick_victim(&mut self) -> Option<Key> {
+        self.queue.peek().map(|(key, _)| key.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    fn key(name: &str) -> Key {
+        name.to_string()
+    }
+
+    // evicts keys one by one, the way cache does, and returns them in eviction order
+    fn evict_all(policy: &mut dyn EvictionPolicy) -> Vec<Key> {
+        let mut victims = Vec::new();
+        while let Some(victim) = policy.pick_victim() {
+            policy.on_delete(&victim);
+            victims.push(victim);
+        }
+        victims
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_accessed_first() {
+        let mut policy = LruPolicy::default();
+        for name in ["a", "b", "c"] {
+            policy.on_insert(&key(name), None);
+        }
+        policy.on_access(&key("a"));
+        assert_eq!(evict_all(&mut policy), ["b", "c", "a"]);
+    }
+
+    #[test]
+    fn lfu_evicts_least_frequently_accessed_first() {
+        let mut policy = LfuPolicy::default();
+        for name in ["a", "b", "c"] {
+            policy.on_insert(&key(name), None);
+        }
+        policy.on_access(&key("a"));
+        policy.on_access(&key("a"));
+        policy.on_access(&key("c"));
+        // "b" and "c" were accessed as many times, "b" less recently
+        policy.on_access(&key("b"));
+        assert_eq!(evict_all(&mut policy), ["c", "b", "a"]);
+    }
+
+    #[test]
+    fn fifo_ignores_accesses_and_overwrites() {
+        let mut policy = FifoPolicy::default();
+        for name in ["a", "b", "c"] {
+            policy.on_insert(&key(name), None);
+        }
+        policy.on_access(&key("a"));
+        policy.on_insert(&key("a"), None);
+        assert_eq!(evict_all(&mut policy), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn random_evicts_every_key_once() {
+        let mut policy = RandomPolicy::default();
+        for name in ["a", "b", "c"] {
+            policy.on_insert(&key(name), None);
+        }
+        policy.on_delete(&key("b"));
+        let mut victims = evict_all(&mut policy);
+        victims.sort();
+        assert_eq!(victims, ["a", "c"]);
+    }
+
+    #[test]
+    fn volatile_ttl_evicts_soonest_expiring_and_skips_persistent_keys() {
+        let mut policy = VolatileTtlPolicy::default();
+        let now = SystemTime::now();
+        policy.on_insert(&key("late"), Some(now + Duration::from_secs(20)));
+        policy.on_insert(&key("persistent"), None);
+        policy.on_insert(&key("soon"), Some(now + Duration::from_secs(10)));
+        policy.on_insert(&key("persisted"), Some(now + Duration::from_secs(5)));
+        policy.on_expiration_change(&key("persisted"), None);
+        assert_eq!(evict_all(&mut policy), ["soon", "late"]);
+    }
+
+    #[test]
+    fn random_key_set_keeps_positions_after_removal() {
+        let mut keys = RandomKeySet::default();
+        for name in ["a", "b", "c"] {
+            keys.insert(&key(name));
+        }
+        keys.remove(&key("a"));
+        keys.remove(&key("c"));
+        assert_eq!(keys.random_key(), Some(&key("b")));
+        keys.remove(&key("b"));
+        assert_eq!(keys.random_key(), None);
+    }
+}
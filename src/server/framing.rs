@@ -0,0 +1,193 @@
+use std::io::{self, Read, Write};
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Connection using frames starts with a handshake: the side which opened it sends `FRAME_MAGIC`
+// followed by the newest frame version it supports, and the other side answers with `FRAME_MAGIC`
+// followed by the version both of them will use, or 0 if there is no such version.
+// After that every message is sent as a frame: big-endian u32 length and MessagePack message of that length.
+// Connections to other nodes are opened by blocking code, connections from clients and other nodes
+// are accepted by the event loop, so reading has both blocking and async versions.
+
+// first byte is never used in MessagePack, so handshake can't be confused with any other wire format
+pub const FRAME_MAGIC: [u8; 3] = [0xc1, b'R', b'C'];
+// frame versions this node understands
+const MIN_FRAME_VERSION: u8 = 1;
+const MAX_FRAME_VERSION: u8 = 1;
+// protects from allocating whatever length the other side announces
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// Sends handshake on a connection this node opened, returns the agreed frame version.
+pub fn request_handshake<S: Read + Write>(stream: &mut S) -> io::Result<u8> {
+    stream.write_all(&handshake(MAX_FRAME_VERSION))?;
+    stream.flush()?;
+    let mut response = [0; 4];
+    stream.read_exact(&mut response)?;
+    let version = parse_handshake(response)?;
+    if !(MIN_FRAME_VERSION..=MAX_FRAME_VERSION).contains(&version) {
+        return Err(invalid_data(format!("other side doesn't support frame versions up to {MAX_FRAME_VERSION}")));
+    }
+    Ok(version)
+}
+
+/// Answers handshake on a connection other side opened, returns the agreed frame version.
+pub async fn accept_handshake<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(reader: &mut R, writer: &mut W) -> io::Result<u8> {
+    let mut request = [0; 4];
+    reader.read_exact(&mut request).await?;
+    let requested_version = parse_handshake(request)?;
+    let version = requested_version.min(MAX_FRAME_VERSION);
+    if version < MIN_FRAME_VERSION {
+        writer.write_all(&handshake(0)).await?;
+        writer.flush().await?;
+        return Err(invalid_data(format!("frame version {requested_version} is not supported")));
+    }
+    writer.write_all(&handshake(version)).await?;
+    writer.flush().await?;
+    Ok(version)
+}
+
+fn handshake(version: u8) -> [u8; 4] {
+    [FRAME_MAGIC[0], FRAME_MAGIC[1], FRAME_MAGIC[2], version]
+}
+
+fn parse_handshake(handshake: [u8; 4]) -> io::Result<u8> {
+    if handshake[..3] != FRAME_MAGIC {
+        return Err(invalid_data("handshake doesn't start with frame magic".to_string()));
+    }
+    Ok(handshake[3])
+}
+
+/// Encodes the message as a frame, ready to be written to a connection.
+pub fn encode_frame<T: Serialize>(message: &T) -> io::Result<Vec<u8>> {
+    // with field names, so the other side doesn't depend on the order of fields
+    let payload = rmp_serde::to_vec_named(message).map_err(io::Error::other)?;
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(invalid_data(format!("frame of {} bytes is too long", payload.len())));
+    }
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    writer.write_all(&encode_frame(message)?)?;
+    writer.flush()
+}
+
+// Returns payload of the next frame, to be decoded with `rmp_serde::from_slice`.
+// Frame boundaries are known, so a payload which can't be decoded can be skipped.
+// Returns none if connection is closed between frames
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    if reader.read(&mut len[..1])? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len[1..])?;
+    let len = frame_len(len)?;
+    // payload grows as it arrives, so announcing a long frame doesn't allocate its whole length up front
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    check_payload_len(&payload, len)?;
+    Ok(Some(payload))
+}
+
+pub async fn read_frame_async<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    if reader.read(&mut len[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len[1..]).await?;
+    let len = frame_len(len)?;
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload).await?;
+    check_payload_len(&payload, len)?;
+    Ok(Some(payload))
+}
+
+fn frame_len(header: [u8; 4]) -> io::Result<usize> {
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid_data(format!("frame of {len} bytes is too long")));
+    }
+    Ok(len)
+}
+
+// connection closed before the whole payload arrived
+fn check_payload_len(payload: &[u8], len: usize) -> io::Result<()> {
+    if payload.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::test_util::block_on;
+    use super::*;
+
+    // errors of the blocking and the async reads
+    fn read_errors(bytes: &[u8]) -> [io::ErrorKind; 2] {
+        [
+            read_frame(&mut &*bytes).unwrap_err().kind(),
+            block_on(read_frame_async(&mut &*bytes)).unwrap_err().kind(),
+        ]
+    }
+
+    #[test]
+    fn frames_are_read_one_after_another() {
+        let bytes = [encode_frame(&"first").unwrap(), encode_frame(&[1, 2, 3]).unwrap()].concat();
+        let mut reader = bytes.as_slice();
+        let first = read_frame(&mut reader).unwrap().unwrap();
+        assert_eq!(rmp_serde::from_slice::<String>(&first).unwrap(), "first");
+        let second = read_frame(&mut reader).unwrap().unwrap();
+        assert_eq!(rmp_serde::from_slice::<Vec<u8>>(&second).unwrap(), vec![1, 2, 3]);
+        assert!(read_frame(&mut reader).unwrap().is_none());
+
+        let mut reader = bytes.as_slice();
+        block_on(async {
+            let first = read_frame_async(&mut reader).await.unwrap().unwrap();
+            assert_eq!(rmp_serde::from_slice::<String>(&first).unwrap(), "first");
+            assert!(read_frame_async(&mut reader).await.unwrap().is_some());
+            assert!(read_frame_async(&mut reader).await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn length_over_the_limit_is_rejected_before_reading() {
+        let header = (MAX_FRAME_LEN as u32 + 1).to_be_bytes();
+        assert_eq!(read_errors(&header), [io::ErrorKind::InvalidData; 2]);
+
+        let header = (MAX_FRAME_LEN as u32).to_be_bytes();
+        assert_eq!(read_errors(&header), [io::ErrorKind::UnexpectedEof; 2]);
+    }
+
+    #[test]
+    fn connection_closed_inside_a_frame_is_an_error() {
+        let frame = encode_frame(&"message").unwrap();
+        for len in [2, frame.len() - 1] {
+            assert_eq!(read_errors(&frame[..len]), [io::ErrorKind::UnexpectedEof; 2]);
+        }
+    }
+
+    #[test]
+    fn handshake_agrees_on_supported_version() {
+        let mut response = Vec::new();
+        let version = block_on(accept_handshake(&mut &handshake(MAX_FRAME_VERSION + 1)[..], &mut response)).unwrap();
+        assert_eq!(version, MAX_FRAME_VERSION);
+        assert_eq!(response, handshake(MAX_FRAME_VERSION));
+
+        let mut response = Vec::new();
+        assert!(block_on(accept_handshake(&mut &handshake(0)[..], &mut response)).is_err());
+        assert_eq!(response, handshake(0));
+
+        let mut response = Vec::new();
+        let error = block_on(accept_handshake(&mut &b"*1\r\n"[..], &mut response)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(response.is_empty());
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+use crate::server::cache::Cache;
+use crate::server::cluster::Cluster;
+use crate::server::cluster_command_processing;
+use crate::server::commands::CommandsEnum;
+
+#[derive(Debug)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    // node is reported as suspected after this time without heartbeats
+    pub suspicion_timeout: Duration,
+    // node is removed from the cluster after this time without heartbeats
+    pub failure_timeout: Duration,
+}
+
+pub fn start_heartbeats(cluster: Arc<RwLock<Cluster>>,
+                        cache: Arc<Cache>,
+                        config: HeartbeatConfig,
+) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(config.interval);
+
+            let failed_nodes = {
+                let cluster = cluster.read().unwrap();
+                let heartbeat = CommandsEnum::Heartbeat {
+                    node_id: cluster.self_node_id.clone(),
+                    replication_offsets: cluster.get_replication_offsets(),
+                };
+                for node_id in cluster.get_node_ids() {
+                    cluster.send_command_to_node(&node_id, &heartbeat);
+                }
+                cluster.find_failed_nodes(config.suspicion_timeout, config.failure_timeout)
+            };
+
+            if !failed_nodes.is_empty() {
+                let cluster = cluster.write().unwrap();
+                cluster_command_processing::remove_failed_nodes(failed_nodes, &cluster, &cache);
+            }
+        }
+    });
+}
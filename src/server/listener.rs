@@ -1,115 +1,403 @@
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::process;
+use std::sync::{Arc, RwLock};
 use std::thread;
-use std::time::Duration;
-use log::{info, warn};
-use rayon::ThreadPoolBuilder;
+use log::{error, info, warn};
+use signal_hook::consts::SIGTERM;
+use signal_hook::iterator::Signals;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Builder;
+use tokio::task;
 use crate::server::cache::Cache;
-use crate::server::{cluster_command_processing, user_request_processing};
-use crate::server::cluster::Cluster;
+use crate::server::{cluster_command_processing, framing, heartbeat, memcached, resp, user_request_processing, wire_format};
+use crate::server::cluster::{Cluster, NodeId};
+use crate::server::commands::CommandsEnum;
+use crate::server::heartbeat::HeartbeatConfig;
+use crate::server::memcached::MemcachedCommand;
+use crate::server::requests::{ReqResponseEnum, RequestsEnum};
+use crate::server::resp::{Protocol, RespCommand, RespValue};
+use crate::server::wire_format::WireFormat;
 
-const CLIENT_THREADS: usize = 1;
-const SERVER_THREADS: usize = 3;
-
-pub fn start_server(cache: Cache, 
-                    cluster: Cluster, 
-                    client_port: u32, 
-                    server_port: u32
+pub fn start_server(cache: Cache,
+                    cluster: Cluster,
+                    bind_ip: IpAddr,
+                    client_port: u32,
+                    server_port: u32,
+                    memcached_port: Option<u32>,
+                    heartbeat_config: HeartbeatConfig,
 ) {
-    let client_listener = TcpListener::bind(format!("127.0.0.1:{client_port}")).unwrap();
-    let server_listener = TcpListener::bind(format!("127.0.0.1:{server_port}")).unwrap();
+    // every connection is a task of the event loop, so idle connections don't occupy threads.
+    // Processing requests and commands blocks on locks and on connections to other nodes,
+    // so it runs in `block_in_place`, which hands other tasks of the worker over to another thread
+    let runtime = Builder::new_multi_thread().enable_io().build().unwrap();
+    let client_listener = runtime.block_on(TcpListener::bind((bind_ip, client_port as u16))).unwrap();
+    let server_listener = runtime.block_on(TcpListener::bind((bind_ip, server_port as u16))).unwrap();
+    let memcached_listener = memcached_port.map(|port| runtime.block_on(TcpListener::bind((bind_ip, port as u16))).unwrap());
 
-    let cluster_state = Arc::new(Mutex::new(cluster));
+    // client requests and one-way commands share the read lock and run in parallel,
+    // commands changing cluster state take the write lock, so they never interleave with requests
+    let cluster_state = Arc::new(RwLock::new(cluster));
     let client_cluster = Arc::clone(&cluster_state);
+    let memcached_cluster = Arc::clone(&cluster_state);
     let server_cluster = Arc::clone(&cluster_state);
 
-    let shared_cache = Arc::new(Mutex::new(cache));
+    // cache locks its shards internally
+    let shared_cache = Arc::new(cache);
+    let client_cache = Arc::clone(&shared_cache);
+    let memcached_cache = Arc::clone(&shared_cache);
+    let server_cache = Arc::clone(&shared_cache);
+
+    let server_connections = runtime.spawn(async move {
+        loop {
+            match server_listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_server_connection(stream, Arc::clone(&server_cluster), Arc::clone(&server_cache)));
+                }
+                Err(e) => warn!("Couldn't accept node connection: {e}"),
+            }
+        }
+    });
+    // leader connects back to the cluster port of joining node, so it has to be accepted before joining
+    join_cluster(&cluster_state);
+
+    heartbeat::start_heartbeats(Arc::clone(&cluster_state), Arc::clone(&shared_cache), heartbeat_config);
+
+    let mut signals = Signals::new([SIGTERM]).unwrap();
+    let signal_cluster = Arc::clone(&cluster_state);
+    let signal_cache = Arc::clone(&shared_cache);
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            warn!("Received SIGTERM, leaving the cluster");
+            let cluster = signal_cluster.write().unwrap();
+            cluster_command_processing::leave_cluster(&cluster, &signal_cache);
+            process::exit(0);
+        }
+    });
 
-    let client_threads = thread::spawn(move || {
-        let client_pool = ThreadPoolBuilder::new().num_threads(CLIENT_THREADS).build().unwrap();
-        for stream in client_listener.incoming() {
-            let client_cache_clone_per_connection = Arc::clone(&shared_cache);
-            let client_cluster_status_per_connection = Arc::clone(&client_cluster);
-            client_pool.spawn(move || {
-                handle_client_connection(stream.unwrap(), client_cluster_status_per_connection, client_cache_clone_per_connection);
-            });
+    let client_connections = runtime.spawn(async move {
+        loop {
+            match client_listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(close_on_error("Client", handle_client_connection(stream, Arc::clone(&client_cluster), Arc::clone(&client_cache))));
+                }
+                Err(e) => warn!("Couldn't accept client connection: {e}"),
+            }
         }
     });
-    let server_threads = thread::spawn(move || {
-        // if new nodes never disconnect, we need at least number of threads = number of expected nodes
-        let server_pool = ThreadPoolBuilder::new().num_threads(SERVER_THREADS).build().unwrap();
-        for stream in server_listener.incoming() {
-            let server_cluster_status_per_connection = Arc::clone(&server_cluster);
-            server_pool.spawn(move || {
-                handle_server_connection(stream.unwrap(), server_cluster_status_per_connection);
-            });
+    // memcached clients go through the same request processing as other clients
+    let memcached_connections = memcached_listener.map(|memcached_listener| runtime.spawn(async move {
+        loop {
+            match memcached_listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(close_on_error("Memcached client", handle_memcached_connection(stream, Arc::clone(&memcached_cluster), Arc::clone(&memcached_cache))));
+                }
+                Err(e) => warn!("Couldn't accept memcached connection: {e}"),
+            }
+        }
+    }));
+
+    runtime.block_on(async {
+        client_connections.await.unwrap();
+        server_connections.await.unwrap();
+        if let Some(memcached_connections) = memcached_connections {
+            memcached_connections.await.unwrap();
         }
     });
+}
 
-    client_threads.join().unwrap();
-    server_threads.join().unwrap();
+fn join_cluster(cluster: &Arc<RwLock<Cluster>>) {
+    if let Err(e) = Cluster::join(cluster) {
+        error!("Couldn't join the cluster: {e}");
+        process::exit(1);
+    }
 }
 
-fn handle_client_connection(stream: TcpStream, 
-                            cluster: Arc<Mutex<Cluster>>, 
-                            cache: Arc<Mutex<Cache>>
-) {
-    let mut reader = BufReader::new(stream.try_clone().unwrap());
-    let mut writer = BufWriter::new(stream.try_clone().unwrap());
+// Handlers return once the other side closes the connection, or it can't be used anymore.
+// Dropping the handler closes the connection, so only the error needs to be reported
+async fn close_on_error(connection: &str, handler: impl Future<Output = io::Result<()>>) {
+    if let Err(e) = handler.await {
+        warn!("{connection} connection closed after error: {e}");
+    }
+}
+
+async fn handle_client_connection(stream: TcpStream,
+                                  cluster: Arc<RwLock<Cluster>>,
+                                  cache: Arc<Cache>
+) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let Some(wire_format) = WireFormat::detect(&mut reader).await? else {
+        return Ok(());
+    };
+    info!("Client connected using {wire_format:?}");
+    if !accept_wire_format(wire_format, &mut reader, &mut writer).await {
+        return Ok(());
+    }
+    if let WireFormat::Resp = wire_format {
+        return handle_resp_connection(reader, writer, &cluster, &cache).await;
+    }
     loop {
-        let mut s = String::new();
-        reader.read_line(&mut s).unwrap();
-        info!("Received client request: {s}");
-        match serde_json::from_str(&s) {
-            Ok(request) => {
-                let mut cache = cache.lock().unwrap();
-                let mut cluster = cluster.lock().unwrap();
-                let response = user_request_processing::process_client_request(request, &mut cache, &mut cluster);
-                let mut response_str = serde_json::to_string(&response).unwrap();
-                response_str.push('\n');
-
-                writer.write_all(response_str.as_bytes()).unwrap();
-                writer.flush().unwrap();
-            }
-            Err(_e) => {
-                warn!("Couldn't parse client request: {s}")
+        let request = match wire_format {
+            WireFormat::Json => {
+                let mut s = String::new();
+                if reader.read_line(&mut s).await? == 0 {
+                    info!("Client disconnected");
+                    return Ok(());
+                }
+                info!("Received client request: {s}");
+                match serde_json::from_str::<RequestsEnum>(&s) {
+                    Ok(request) => request,
+                    Err(_e) => {
+                        warn!("Couldn't parse client request: {s}");
+                        continue;
+                    }
+                }
+            }
+            WireFormat::MessagePack => {
+                match wire_format::read_message_pack::<_, RequestsEnum>(&mut reader).await {
+                    Ok(Some(request)) => {
+                        info!("Received client request: {request:?}");
+                        request
+                    }
+                    Ok(None) => {
+                        info!("Client disconnected");
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        // there are no message boundaries to skip to, so the rest of the stream can't be read
+                        warn!("Couldn't read client request, closing connection: {e}");
+                        return Ok(());
+                    }
+                }
+            }
+            WireFormat::Framed => {
+                match framing::read_frame_async(&mut reader).await {
+                    Ok(Some(payload)) => match rmp_serde::from_slice::<RequestsEnum>(&payload) {
+                        Ok(request) => {
+                            info!("Received client request: {request:?}");
+                            request
+                        }
+                        Err(e) => {
+                            // unlike JSON lines, frame boundaries are known, so client can be told about the error
+                            warn!("Couldn't parse client request: {e}");
+                            wire_format.write(&mut writer, &ReqResponseEnum::ErrorProcessingCommand {}).await?;
+                            continue;
+                        }
+                    },
+                    Ok(None) => {
+                        info!("Client disconnected");
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        warn!("Couldn't read client request, closing connection: {e}");
+                        return Ok(());
+                    }
+                }
             }
+            WireFormat::Resp => unreachable!(),
+        };
+
+        let response = task::block_in_place(|| process_request(request, &cluster, &cache));
+        wire_format.write(&mut writer, &response).await?;
+        if matches!(response, ReqResponseEnum::LeftCluster) {
+            info!("Node left the cluster, shutting down");
+            process::exit(0);
         }
     }
 }
 
-fn handle_server_connection(stream: TcpStream, cluster: Arc<Mutex<Cluster>>) {
-    let mut reader = BufReader::new(stream.try_clone().unwrap());
-    let mut writer = BufWriter::new(stream.try_clone().unwrap());
+async fn handle_resp_connection(mut reader: BufReader<OwnedReadHalf>,
+                                mut writer: BufWriter<OwnedWriteHalf>,
+                                cluster: &Arc<RwLock<Cluster>>,
+                                cache: &Cache
+) -> io::Result<()> {
+    // every connection starts with RESP2 and can switch with HELLO
+    let mut protocol = Protocol::Resp2;
     loop {
-        let mut s = String::new();
-        match reader.read_line(&mut s) {
-            Ok(usize) => {
-                if usize == 0 {
-                    continue;
-                }
+        let args = match resp::read_command(&mut reader).await {
+            Ok(Some(args)) => args,
+            Ok(None) => {
+                info!("Client disconnected");
+                return Ok(());
+            }
+            Err(e) => {
+                warn!("Couldn't read client command, closing connection: {e}");
+                return Ok(());
+            }
+        };
+        let reply = match resp::parse_command(args) {
+            RespCommand::Request { request, with_scores } => {
+                info!("Received client request: {request:?}");
+                let response = task::block_in_place(|| process_request(request, cluster, cache));
+                resp::to_reply(response, with_scores, protocol)
+            }
+            RespCommand::Hello { protocol: requested } => {
+                protocol = requested.unwrap_or(protocol);
+                resp::hello_reply(protocol)
+            }
+            RespCommand::Quit => {
+                return write_resp_reply(&mut writer, RespValue::SimpleString("OK".to_string()), protocol).await;
+            }
+            RespCommand::Reply(reply) => reply,
+        };
+        write_resp_reply(&mut writer, reply, protocol).await?;
+    }
+}
+
+async fn write_resp_reply(writer: &mut BufWriter<OwnedWriteHalf>, reply: RespValue, protocol: Protocol) -> io::Result<()> {
+    // replies are encoded in memory, as the encoder writes them piece by piece
+    let mut buffer = Vec::new();
+    reply.write(&mut buffer, protocol)?;
+    writer.write_all(&buffer).await?;
+    writer.flush().await
+}
+
+async fn handle_memcached_connection(stream: TcpStream,
+                                     cluster: Arc<RwLock<Cluster>>,
+                                     cache: Arc<Cache>
+) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    loop {
+        let command = match memcached::read_command(&mut reader).await {
+            Ok(Some(command)) => command,
+            Ok(None) => {
+                info!("Memcached client disconnected");
+                return Ok(());
+            }
+            Err(e) => {
+                warn!("Couldn't read memcached command, closing connection: {e}");
+                return Ok(());
+            }
+        };
+        let reply = match command {
+            Ok(MemcachedCommand::Quit) => return Ok(()),
+            Ok(command) => {
+                info!("Received memcached command: {command:?}");
+                task::block_in_place(|| memcached::execute(command, |request| process_request(request, &cluster, &cache)))
+            }
+            Err(error) => Some(format!("{error}\r\n").into_bytes()),
+        };
+        if let Some(reply) = reply {
+            writer.write_all(&reply).await?;
+            writer.flush().await?;
+        }
+    }
+}
 
-                info!("Received cluster command: {s}");
-                match serde_json::from_str(&s) {
-                    Ok(command) => {
-                        let mut cluster = cluster.lock().unwrap();
-                        let response = cluster_command_processing::process_cluster_command(command, &mut cluster, stream.try_clone().unwrap());
-                        let mut response_str = serde_json::to_string(&response).unwrap();
-                        response_str.push('\n');
+fn process_request(request: RequestsEnum, cluster: &Arc<RwLock<Cluster>>, cache: &Cache) -> ReqResponseEnum {
+    // leaving hands buckets over, so it needs the cluster state for itself
+    if matches!(request, RequestsEnum::LeaveCluster) {
+        let cluster = cluster.write().unwrap();
+        user_request_processing::process_client_request(request, cache, &cluster)
+    } else {
+        let cluster = cluster.read().unwrap();
+        user_request_processing::process_client_request(request, cache, &cluster)
+    }
+}
 
-                        writer.write_all(response_str.as_bytes()).unwrap();
-                        writer.flush().unwrap();
+async fn handle_server_connection(stream: TcpStream,
+                                  cluster: Arc<RwLock<Cluster>>,
+                                  cache: Arc<Cache>
+) {
+    // nodes send heartbeats over the connection they send commands with, so it is known which node
+    // the connection belongs to after the first one. Connections opened just to join the cluster have no node
+    let mut peer_node = None;
+    close_on_error("Node", handle_node_commands(stream, &cluster, &cache, &mut peer_node)).await;
+    if let Some(node_id) = peer_node {
+        task::block_in_place(|| cluster.read().unwrap().mark_node_disconnected(&node_id));
+    }
+}
+
+async fn handle_node_commands(stream: TcpStream,
+                              cluster: &Arc<RwLock<Cluster>>,
+                              cache: &Cache,
+                              peer_node: &mut Option<NodeId>,
+) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    // nodes talk to each other using frames, JSON lines are still accepted for debugging
+    let Some(wire_format) = WireFormat::detect(&mut reader).await? else {
+        return Ok(());
+    };
+    if !accept_wire_format(wire_format, &mut reader, &mut writer).await {
+        return Ok(());
+    }
+    loop {
+        let command = match wire_format {
+            WireFormat::Framed => {
+                match framing::read_frame_async(&mut reader).await {
+                    Ok(Some(payload)) => rmp_serde::from_slice::<CommandsEnum>(&payload).map_err(|e| e.to_string()),
+                    Ok(None) => {
+                        info!("Node disconnected");
+                        return Ok(());
                     }
-                    Err(_e) => {
-                        warn!("Couldn't parse command: {s}")
+                    Err(e) => {
+                        warn!("Couldn't read cluster command, closing connection: {e}");
+                        return Ok(());
                     }
                 }
             }
-            Err(_) => {
-                warn!("Error reading from stream")
+            WireFormat::Json => {
+                let mut s = String::new();
+                if reader.read_line(&mut s).await? == 0 {
+                    info!("Node disconnected");
+                    return Ok(());
+                }
+                serde_json::from_str::<CommandsEnum>(&s).map_err(|_| s)
+            }
+            WireFormat::MessagePack | WireFormat::Resp => {
+                warn!("Nodes can't use {wire_format:?} on cluster port, closing connection");
+                return Ok(());
+            }
+        };
+
+        match command {
+            Ok(command) => {
+                info!("Received cluster command: {command:?}");
+                if let CommandsEnum::Heartbeat { node_id, .. } = &command {
+                    peer_node.get_or_insert_with(|| node_id.clone());
+                }
+                let is_one_way = command.is_one_way();
+                let response = task::block_in_place(|| if command.changes_cluster_state() {
+                    let cluster = cluster.write().unwrap();
+                    cluster_command_processing::process_cluster_command(command, &cluster, cache)
+                } else {
+                    let cluster = cluster.read().unwrap();
+                    cluster_command_processing::process_cluster_command(command, &cluster, cache)
+                });
+                if is_one_way {
+                    continue;
+                }
+                wire_format.write(&mut writer, &response).await?;
+            }
+            Err(e) => {
+                warn!("Couldn't parse command: {e}")
+            }
+        }
+    }
+}
+
+// Completes the handshake if the other side uses frames, returns false if connection can't be used
+async fn accept_wire_format(wire_format: WireFormat,
+                            reader: &mut BufReader<OwnedReadHalf>,
+                            writer: &mut BufWriter<OwnedWriteHalf>,
+) -> bool {
+    if let WireFormat::Framed = wire_format {
+        match framing::accept_handshake(reader, writer).await {
+            Ok(version) => info!("Agreed on frame version {version}"),
+            Err(e) => {
+                warn!("Frame handshake failed, closing connection: {e}");
+                return false;
             }
         }
-        thread::sleep(Duration::from_millis(100));
     }
+    true
 }
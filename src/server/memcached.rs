@@ -0,0 +1,358 @@
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+use crate::server::cache::Key;
+use crate::server::requests::{ReqResponseEnum, RequestsEnum};
+use crate::server::values::Value;
+
+// memcached treats expiration times longer than 30 days as unix timestamps
+const MAX_RELATIVE_EXPIRATION: i64 = 60 * 60 * 24 * 30;
+// default item size limit of memcached
+const MAX_VALUE_LEN: usize = 1024 * 1024;
+const MAX_KEY_LEN: usize = 250;
+// command line limit of memcached, data blocks are read separately
+const MAX_LINE_LEN: usize = 2048;
+
+#[derive(Debug)]
+pub enum StoreMode {
+    Set,
+    // only if there is no such key
+    Add,
+    // only if key already exists
+    Replace,
+    // only if value still has the version returned by `gets`
+    Cas(u64),
+}
+
+/// Command of memcached text protocol. Flags sent with stored values are not kept,
+/// values are always returned with flags 0.
+#[derive(Debug)]
+pub enum MemcachedCommand {
+    Get {
+        keys: Vec<Key>,
+        // `gets` returns versions of values, to be used in `cas`
+        with_cas: bool,
+    },
+    Store {
+        mode: StoreMode,
+        key: Key,
+        value: Value,
+        exptime: i64,
+        noreply: bool,
+    },
+    Delete {
+        key: Key,
+        noreply: bool,
+    },
+    Incr {
+        key: Key,
+        delta: u64,
+        decrement: bool,
+        noreply: bool,
+    },
+    Touch {
+        key: Key,
+        exptime: i64,
+        noreply: bool,
+    },
+    Version,
+    Quit,
+}
+
+// Reads a command line, and the data block following it for storage commands.
+// Returns none if connection is closed, and the error line to send back if command is invalid
+pub async fn read_command<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Result<MemcachedCommand, String>>> {
+    let mut line = Vec::new();
+    if reader.take(MAX_LINE_LEN as u64).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    if line.len() == MAX_LINE_LEN && !line.ends_with(b"\n") {
+        skip_line(reader).await?;
+        return Ok(Some(Err(client_error("line too long"))));
+    }
+    let Ok(line) = String::from_utf8(line) else {
+        return Ok(Some(Err(client_error("keys must be valid UTF-8"))));
+    };
+    let tokens: Vec<&str> = line.split_ascii_whitespace().collect();
+    // data block has to be consumed even if the rest of the command line is invalid
+    let value = match tokens.as_slice() {
+        ["set" | "add" | "replace" | "cas", _, _, _, len, ..] => match len.parse() {
+            Ok(len) => Some(read_data_block(reader, len).await?),
+            Err(_) => return Ok(Some(Err(client_error("bad command line format")))),
+        },
+        _ => None,
+    };
+    Ok(Some(parse_command(&tokens, value)))
+}
+
+// rest of the line is read in chunks, so its length doesn't matter
+async fn skip_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<()> {
+    let mut chunk = Vec::new();
+    loop {
+        chunk.clear();
+        if reader.take(MAX_LINE_LEN as u64).read_until(b'\n', &mut chunk).await? == 0 || chunk.ends_with(b"\n") {
+            return Ok(());
+        }
+    }
+}
+
+async fn read_data_block<R: AsyncBufRead + Unpin>(reader: &mut R, len: usize) -> io::Result<Result<Value, String>> {
+    // data block is followed by CRLF
+    if len > MAX_VALUE_LEN {
+        tokio::io::copy(&mut reader.take(len as u64 + 2), &mut tokio::io::sink()).await?;
+        return Ok(Err("SERVER_ERROR object too large for cache".to_string()));
+    }
+    let mut data = vec![0; len + 2];
+    reader.read_exact(&mut data).await?;
+    if !data.ends_with(b"\r\n") {
+        return Ok(Err(client_error("bad data chunk")));
+    }
+    data.truncate(len);
+    Ok(Ok(Value::from(data)))
+}
+
+fn parse_command(tokens: &[&str], value: Option<Result<Value, String>>) -> Result<MemcachedCommand, String> {
+    match tokens {
+        [name @ ("get" | "gets"), keys @ ..] if !keys.is_empty() => Ok(MemcachedCommand::Get {
+            keys: keys.iter().map(|key| to_key(key)).collect::<Result<_, _>>()?,
+            with_cas: *name == "gets",
+        }),
+        [name @ ("set" | "add" | "replace"), key, flags, exptime, _, rest @ ..] => {
+            let mode = match *name {
+                "set" => StoreMode::Set,
+                "add" => StoreMode::Add,
+                _ => StoreMode::Replace,
+            };
+            to_store_command(mode, key, flags, exptime, value, rest)
+        }
+        ["cas", key, flags, exptime, _, version, rest @ ..] => {
+            to_store_command(StoreMode::Cas(to_number(version)?), key, flags, exptime, value, rest)
+        }
+        ["delete", key, rest @ ..] => Ok(MemcachedCommand::Delete { key: to_key(key)?, noreply: to_noreply(rest)? }),
+        [name @ ("incr" | "decr"), key, delta, rest @ ..] => {
+            Ok(MemcachedCommand::Incr {
+                key: to_key(key)?,
+                delta: delta.parse().map_err(|_| client_error("invalid numeric delta argument"))?,
+                decrement: *name == "decr",
+                noreply: to_noreply(rest)?,
+            })
+        }
+        ["touch", key, exptime, rest @ ..] => Ok(MemcachedCommand::Touch {
+            key: to_key(key)?,
+            exptime: to_number(exptime)?,
+            noreply: to_noreply(rest)?,
+        }),
+        ["version"] => Ok(MemcachedCommand::Version),
+        ["quit"] => Ok(MemcachedCommand::Quit),
+        _ => Err("ERROR".to_string()),
+    }
+}
+
+fn to_store_command(mode: StoreMode,
+                    key: &str,
+                    flags: &str,
+                    exptime: &str,
+                    value: Option<Result<Value, String>>,
+                    rest: &[&str],
+) -> Result<MemcachedCommand, String> {
+    let key = to_key(key)?;
+    to_number::<u32>(flags)?;
+    Ok(MemcachedCommand::Store {
+        mode,
+        key,
+        value: value.expect("data block is read for every storage command")?,
+        exptime: to_number(exptime)?,
+        noreply: to_noreply(rest)?,
+    })
+}
+
+fn to_key(key: &str) -> Result<Key, String> {
+    if key.len() > MAX_KEY_LEN || key.chars().any(char::is_control) {
+        return Err(client_error("bad key"));
+    }
+    Ok(key.to_string())
+}
+
+fn to_number<T: std::str::FromStr>(number: &str) -> Result<T, String> {
+    number.parse().map_err(|_| client_error("bad command line format"))
+}
+
+fn to_noreply(rest: &[&str]) -> Result<bool, String> {
+    match rest {
+        [] => Ok(false),
+        ["noreply"] => Ok(true),
+        _ => Err(client_error("bad command line format")),
+    }
+}
+
+fn client_error(message: &str) -> String {
+    format!("CLIENT_ERROR {message}")
+}
+
+// 0 means no expiration, negative expiration time means the key is already expired
+fn to_ttl(exptime: i64) -> Option<u64> {
+    match exptime {
+        0 => None,
+        ..0 => Some(0),
+        1..=MAX_RELATIVE_EXPIRATION => Some(exptime as u64),
+        _ => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            Some((exptime as u64).saturating_sub(now))
+        }
+    }
+}
+
+/// Executes the command with `process`, which handles requests the same way as requests in other protocols.
+/// Returns the reply to send, none if client asked for no reply.
+pub fn execute(command: MemcachedCommand, process: impl Fn(RequestsEnum) -> ReqResponseEnum) -> Option<Vec<u8>> {
+    match command {
+        MemcachedCommand::Get { keys, with_cas } => {
+            let mut reply = Vec::new();
+            for key in keys {
+                // keys holding lists or other collections are reported as misses
+                if let ReqResponseEnum::Get { value: Some(value), version: Some(version), .. } = process(RequestsEnum::Get { key: key.clone() }) {
+                    let cas = if with_cas { format!(" {version}") } else { String::new() };
+                    reply.extend_from_slice(format!("VALUE {key} 0 {}{cas}\r\n", value.len()).as_bytes());
+                    reply.extend_from_slice(&value);
+                    reply.extend_from_slice(b"\r\n");
+                }
+            }
+            reply.extend_from_slice(b"END\r\n");
+            Some(reply)
+        }
+        MemcachedCommand::Store { mode, key, value, exptime, noreply } => {
+            let ttl = to_ttl(exptime);
+            let response = match mode {
+                StoreMode::Set => process(RequestsEnum::Put { key, value, ttl }),
+                StoreMode::Add => process(RequestsEnum::PutIfAbsent { key, value, ttl }),
+                StoreMode::Replace => process(RequestsEnum::PutIfPresent { key, value, ttl }),
+                StoreMode::Cas(version) => {
+                    let response = process(RequestsEnum::Cas { key: key.clone(), value, ttl, version });
+                    if !matches!(response, ReqResponseEnum::ConditionalPut { stored: true, .. }) {
+                        // value was either changed or removed since it was read
+                        let exists = key_exists(&process, key);
+                        return to_reply(if exists { "EXISTS" } else { "NOT_FOUND" }, noreply);
+                    }
+                    response
+                }
+            };
+            let stored = matches!(response, ReqResponseEnum::Put | ReqResponseEnum::ConditionalPut { stored: true, .. });
+            to_reply(if stored { "STORED" } else { "NOT_STORED" }, noreply)
+        }
+        MemcachedCommand::Delete { key, noreply } => {
+            let deleted = matches!(process(RequestsEnum::Delete { key }), ReqResponseEnum::Delete { deleted: true });
+            to_reply(if deleted { "DELETED" } else { "NOT_FOUND" }, noreply)
+        }
+        MemcachedCommand::Incr { key, delta, decrement, noreply } => {
+            match process(RequestsEnum::IncrExisting { key, delta, decrement }) {
+                ReqResponseEnum::IncrExisting { value: Some(value) } => to_reply(&value.to_string(), noreply),
+                ReqResponseEnum::IncrExisting { value: None } => to_reply("NOT_FOUND", noreply),
+                _ => to_reply(&client_error("cannot increment or decrement non-numeric value"), noreply),
+            }
+        }
+        MemcachedCommand::Touch { key, exptime, noreply } => {
+            let touched = match to_ttl(exptime) {
+                Some(ttl) => matches!(process(RequestsEnum::Expire { key, ttl }), ReqResponseEnum::Expire { updated: true }),
+                // Persist also fails for keys without TTL, which still count as touched
+                None => matches!(process(RequestsEnum::Persist { key: key.clone() }), ReqResponseEnum::Persist { persisted: true })
+                    || key_exists(&process, key),
+            };
+            to_reply(if touched { "TOUCHED" } else { "NOT_FOUND" }, noreply)
+        }
+        MemcachedCommand::Version => to_reply(&format!("VERSION {}", env!("CARGO_PKG_VERSION")), false),
+        // connection is closed without a reply
+        MemcachedCommand::Quit => None,
+    }
+}
+
+fn key_exists(process: impl Fn(RequestsEnum) -> ReqResponseEnum, key: Key) -> bool {
+    matches!(process(RequestsEnum::Exists { key }), ReqResponseEnum::Exists { exists: true })
+}
+
+fn to_reply(line: &str, noreply: bool) -> Option<Vec<u8>> {
+    if noreply {
+        return None;
+    }
+    Some(format!("{line}\r\n").into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::test_util::block_on;
+    use super::*;
+
+    // reads commands until connection is closed
+    fn read_all(mut bytes: &[u8]) -> Vec<Result<MemcachedCommand, String>> {
+        block_on(async {
+            let mut commands = Vec::new();
+            while let Some(command) = read_command(&mut bytes).await.unwrap() {
+                commands.push(command);
+            }
+            commands
+        })
+    }
+
+    fn read_one(bytes: &[u8]) -> Result<MemcachedCommand, String> {
+        let mut commands = read_all(bytes);
+        assert_eq!(commands.len(), 1);
+        commands.remove(0)
+    }
+
+    #[test]
+    fn too_long_line_is_skipped() {
+        let mut bytes = format!("get {}\r\n", "k".repeat(3 * MAX_LINE_LEN)).into_bytes();
+        bytes.extend_from_slice(b"version\r\n");
+        let commands = read_all(&bytes);
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].as_ref().unwrap_err(), "CLIENT_ERROR line too long");
+        assert!(matches!(commands[1], Ok(MemcachedCommand::Version)));
+    }
+
+    #[test]
+    fn data_block_is_read_with_store_command() {
+        let commands = read_all(b"set k 0 0 5\r\nhello\r\nget k\r\n");
+        assert_eq!(commands.len(), 2);
+        match &commands[0] {
+            Ok(MemcachedCommand::Store { mode: StoreMode::Set, key, value, .. }) => {
+                assert_eq!(key, "k");
+                assert_eq!(&value[..], b"hello");
+            }
+            command => panic!("unexpected command {command:?}"),
+        }
+        assert!(matches!(&commands[1], Ok(MemcachedCommand::Get { keys, with_cas: false }) if keys == &["k"]));
+    }
+
+    #[test]
+    fn bad_data_chunk_is_rejected() {
+        let commands = read_all(b"set k 0 0 2\r\nhello\r\n");
+        assert_eq!(commands[0].as_ref().unwrap_err(), "CLIENT_ERROR bad data chunk");
+    }
+
+    #[test]
+    fn too_large_value_is_skipped() {
+        let mut bytes = format!("set k 0 0 {}\r\n", MAX_VALUE_LEN + 1).into_bytes();
+        bytes.resize(bytes.len() + MAX_VALUE_LEN + 1, b'a');
+        bytes.extend_from_slice(b"\r\nversion\r\n");
+        let commands = read_all(&bytes);
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].as_ref().unwrap_err(), "SERVER_ERROR object too large for cache");
+        assert!(matches!(commands[1], Ok(MemcachedCommand::Version)));
+    }
+
+    #[test]
+    fn malformed_command_lines_are_rejected() {
+        assert_eq!(read_one(b"set k 0 0 x\r\n").unwrap_err(), "CLIENT_ERROR bad command line format");
+        assert_eq!(read_one(b"delete k now\r\n").unwrap_err(), "CLIENT_ERROR bad command line format");
+        assert_eq!(read_one(format!("get {}\r\n", "k".repeat(MAX_KEY_LEN + 1)).as_bytes()).unwrap_err(), "CLIENT_ERROR bad key");
+        assert_eq!(read_one(b"flush_all\r\n").unwrap_err(), "ERROR");
+        assert_eq!(read_one(b"get \xff\r\n").unwrap_err(), "CLIENT_ERROR keys must be valid UTF-8");
+    }
+
+    #[test]
+    fn incr_and_decr_take_unsigned_delta() {
+        let command = read_one(b"decr k 18446744073709551615 noreply\r\n");
+        assert!(matches!(command, Ok(MemcachedCommand::Incr { delta: u64::MAX, decrement: true, noreply: true, .. })));
+        assert!(matches!(read_one(b"incr k 1\r\n"), Ok(MemcachedCommand::Incr { delta: 1, decrement: false, .. })));
+        assert_eq!(read_one(b"incr k -1\r\n").unwrap_err(), "CLIENT_ERROR invalid numeric delta argument");
+    }
+}
@@ -1,19 +1,212 @@
+use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
-use crate::server::cache::{Key, Value};
+use crate::server::cache::{CacheError, CacheStats, Key};
+use crate::server::cluster::NodeId;
+use crate::server::values::{Field, ScoredMember, Value};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum RequestsEnum {
     Put {
         key: Key,
         value: Value,
-        ttl: u64,
+        // in seconds, key without TTL never expires
+        #[serde(default)]
+        ttl: Option<u64>,
     },
     Get {
         key: Key,
     },
+    // put only if there is no such key
+    PutIfAbsent {
+        key: Key,
+        value: Value,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    // put only if key already exists
+    PutIfPresent {
+        key: Key,
+        value: Value,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    // put only if key exists and its value has the given version, returned by Get
+    Cas {
+        key: Key,
+        value: Value,
+        #[serde(default)]
+        ttl: Option<u64>,
+        version: u64,
+    },
     Exists {
         key: Key,
     },
+    Delete {
+        key: Key,
+    },
+    DeleteMany {
+        keys: Vec<Key>,
+    },
+    Ttl {
+        key: Key,
+    },
+    Expire {
+        key: Key,
+        ttl: u64,
+    },
+    ExpireAt {
+        key: Key,
+        // seconds since unix epoch
+        unix_ts: u64,
+    },
+    // removes TTL of the key
+    Persist {
+        key: Key,
+    },
+    // counters are created with value 0 and `ttl` if they don't exist, existing ones keep their TTL
+    Incr {
+        key: Key,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    Decr {
+        key: Key,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    IncrBy {
+        key: Key,
+        delta: i64,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    // changes only existing counters, which are unsigned, see `Cache::incr_existing`. Used for memcached incr and decr
+    IncrExisting {
+        key: Key,
+        delta: u64,
+        decrement: bool,
+    },
+    IncrByFloat {
+        key: Key,
+        delta: f64,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    // lists are created with `ttl` by the first push, and removed when their last item is gone
+    LPush {
+        key: Key,
+        values: Vec<Value>,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    RPush {
+        key: Key,
+        values: Vec<Value>,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    LPop {
+        key: Key,
+    },
+    RPop {
+        key: Key,
+    },
+    // `start` and `stop` are inclusive, negative ones count from the end of the list
+    LRange {
+        key: Key,
+        start: i64,
+        stop: i64,
+    },
+    LLen {
+        key: Key,
+    },
+    LTrim {
+        key: Key,
+        start: i64,
+        stop: i64,
+    },
+    // hashes are created with `ttl` by the first write, and removed when their last field is gone
+    HSet {
+        key: Key,
+        fields: HashMap<Field, Value>,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    HGet {
+        key: Key,
+        field: Field,
+    },
+    HDel {
+        key: Key,
+        fields: Vec<Field>,
+    },
+    HGetAll {
+        key: Key,
+    },
+    HIncrBy {
+        key: Key,
+        field: Field,
+        delta: i64,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    HExists {
+        key: Key,
+        field: Field,
+    },
+    // sets and sorted sets are created with `ttl` by the first write, and removed when their last member is gone
+    SAdd {
+        key: Key,
+        members: Vec<Value>,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    SRem {
+        key: Key,
+        members: Vec<Value>,
+    },
+    SMembers {
+        key: Key,
+    },
+    SIsMember {
+        key: Key,
+        member: Value,
+    },
+    SCard {
+        key: Key,
+    },
+    ZAdd {
+        key: Key,
+        members: Vec<ScoredMember>,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    // members by position in order of their scores, `start` and `stop` work like in LRange
+    ZRange {
+        key: Key,
+        start: i64,
+        stop: i64,
+    },
+    // members with scores between `min` and `max` inclusive
+    ZRangeByScore {
+        key: Key,
+        min: f64,
+        max: f64,
+    },
+    ZRank {
+        key: Key,
+        member: Value,
+    },
+    ZIncrBy {
+        key: Key,
+        member: Value,
+        delta: f64,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    // stats of the node which received the request
+    Stats,
+    LeaveCluster,
     Exit,
 }
 
@@ -23,10 +216,118 @@ pub enum ReqResponseEnum {
     Get {
         key: Key,
         value: Option<Value>,
+        version: Option<u64>,
+    },
+    // response to PutIfAbsent, PutIfPresent and Cas, with new version if value was stored
+    ConditionalPut {
+        stored: bool,
+        version: Option<u64>,
     },
     Exists {
         exists: bool,
     },
+    Delete {
+        deleted: bool,
+    },
+    DeleteMany {
+        deleted: u64,
+    },
+    Ttl {
+        exists: bool,
+        // seconds left, none if key doesn't exist or has no TTL
+        ttl: Option<u64>,
+    },
+    // response to both Expire and ExpireAt
+    Expire {
+        updated: bool,
+    },
+    Persist {
+        persisted: bool,
+    },
+    // response to Incr, Decr and IncrBy
+    Incr {
+        value: i64,
+    },
+    // none if the key doesn't exist
+    IncrExisting {
+        value: Option<u64>,
+    },
+    IncrByFloat {
+        value: f64,
+    },
+    // response to LPush and RPush, with new length of the list
+    Push {
+        len: usize,
+    },
+    // response to LPop and RPop
+    Pop {
+        value: Option<Value>,
+    },
+    LRange {
+        values: Vec<Value>,
+    },
+    LLen {
+        len: usize,
+    },
+    LTrim,
+    HSet {
+        // number of fields which didn't exist before
+        added: usize,
+    },
+    HGet {
+        value: Option<Value>,
+    },
+    HDel {
+        deleted: usize,
+    },
+    HGetAll {
+        fields: HashMap<Field, Value>,
+    },
+    HIncrBy {
+        value: i64,
+    },
+    HExists {
+        exists: bool,
+    },
+    SAdd {
+        // number of members which didn't exist before
+        added: usize,
+    },
+    SRem {
+        removed: usize,
+    },
+    SMembers {
+        members: Vec<Value>,
+    },
+    SIsMember {
+        is_member: bool,
+    },
+    SCard {
+        count: usize,
+    },
+    ZAdd {
+        // number of members which didn't exist before
+        added: usize,
+    },
+    // response to ZRange and ZRangeByScore
+    ZRange {
+        members: Vec<ScoredMember>,
+    },
+    ZRank {
+        rank: Option<usize>,
+    },
+    ZIncrBy {
+        score: f64,
+    },
+    Stats {
+        node_id: NodeId,
+        stats: CacheStats,
+    },
+    LeftCluster,
+    // request is valid, but can't be applied to the current value of the key
+    Error {
+        error: CacheError,
+    },
     ErrorProcessingCommand {},
 }
 
@@ -0,0 +1,546 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::str::FromStr;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+use crate::server::cache::{CacheError, Key};
+use crate::server::framing;
+use crate::server::requests::{ReqResponseEnum, RequestsEnum};
+use crate::server::values::{ScoredMember, Value};
+
+// protect from allocating whatever client announces. Whole command is limited the same as a frame
+const MAX_BULK_LEN: usize = 16 * 1024 * 1024;
+const MAX_COMMAND_LEN: usize = framing::MAX_FRAME_LEN;
+// array and bulk string headers hold only a length
+const MAX_HEADER_LEN: u64 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+/// Reply sent to a Redis client. Types which exist only in RESP3 are downgraded
+/// to their RESP2 counterparts when connection hasn't switched to RESP3 with HELLO.
+#[derive(Debug)]
+pub enum RespValue {
+    SimpleString(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Value),
+    Null,
+    Double(f64),
+    Array(Vec<RespValue>),
+    Map(Vec<(RespValue, RespValue)>),
+    Set(Vec<RespValue>),
+}
+
+/// Command received from a Redis client.
+pub enum RespCommand {
+    // handled by the cache like requests in other wire formats
+    Request {
+        request: RequestsEnum,
+        // ZRANGE and ZRANGEBYSCORE reply with scores too
+        with_scores: bool,
+    },
+    // switches connection to the given protocol version, if there is one
+    Hello {
+        protocol: Option<Protocol>,
+    },
+    Quit,
+    // answered without touching the cache, including errors in the command itself
+    Reply(RespValue),
+}
+
+// Reads a command sent as an array of bulk strings, the way all Redis clients send them.
+// Returns none if connection is closed before the command starts
+pub async fn read_command<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader).await? else {
+        return Ok(None);
+    };
+    let count = parse_length(&line, b'*')?;
+    let mut args = Vec::new();
+    let mut command_len = line.len();
+    for _ in 0..count {
+        let line = read_line(reader).await?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        let len = parse_length(&line, b'$')?;
+        command_len += line.len() + len;
+        if command_len > MAX_COMMAND_LEN {
+            return Err(invalid_data("command is too long"));
+        }
+        // bulk string is followed by CRLF too
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid_data("bulk string is not terminated with CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.take(MAX_HEADER_LEN).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\r\n") {
+        return Err(invalid_data("line is not terminated with CRLF"));
+    }
+    line.truncate(line.len() - 2);
+    Ok(Some(line))
+}
+
+fn parse_length(line: &[u8], prefix: u8) -> io::Result<usize> {
+    match line.split_first() {
+        Some((first, len)) if *first == prefix => {
+            std::str::from_utf8(len).ok()
+                .and_then(|len| len.parse::<usize>().ok())
+                .filter(|len| *len <= MAX_BULK_LEN)
+                .ok_or_else(|| invalid_data("invalid length"))
+        }
+        _ => Err(invalid_data(format!("expected '{}'", prefix as char))),
+    }
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+pub fn parse_command(args: Vec<Vec<u8>>) -> RespCommand {
+    match parse_args(&args) {
+        Ok(command) => command,
+        Err(error) => RespCommand::Reply(RespValue::Error(error)),
+    }
+}
+
+fn parse_args(args: &[Vec<u8>]) -> Result<RespCommand, String> {
+    let Some((name, args)) = args.split_first() else {
+        return Err("ERR empty command".to_string());
+    };
+    let name = String::from_utf8_lossy(name).to_ascii_uppercase();
+    let request = match (name.as_str(), args) {
+        ("PING", []) => return Ok(reply(RespValue::SimpleString("PONG".to_string()))),
+        ("PING" | "ECHO", [message]) => return Ok(reply(RespValue::Bulk(to_value(message)))),
+        ("HELLO", []) => return Ok(RespCommand::Hello { protocol: None }),
+        // authentication and client name are accepted, but ignored
+        ("HELLO", [protocol, ..]) => {
+            let protocol = match protocol.as_slice() {
+                b"2" => Protocol::Resp2,
+                b"3" => Protocol::Resp3,
+                _ => return Err("NOPROTO unsupported protocol version".to_string()),
+            };
+            return Ok(RespCommand::Hello { protocol: Some(protocol) });
+        }
+        ("QUIT", _) => return Ok(RespCommand::Quit),
+        // there is only one database
+        ("SELECT", [db]) => {
+            return match db.as_slice() {
+                b"0" => Ok(reply(ok())),
+                _ => Err("ERR DB index is out of range".to_string()),
+            };
+        }
+        // client libraries send their name and version when connecting
+        ("CLIENT", [subcommand, ..]) if subcommand.eq_ignore_ascii_case(b"SETNAME")
+            || subcommand.eq_ignore_ascii_case(b"SETINFO") => return Ok(reply(ok())),
+        // redis-cli asks for command docs on start, it works fine without them
+        ("COMMAND", _) => return Ok(reply(RespValue::Array(Vec::new()))),
+        ("INFO", _) => RequestsEnum::Stats,
+        ("GET", [key]) => RequestsEnum::Get { key: to_key(key)? },
+        ("SET", [key, value, options @ ..]) => parse_set(key, value, options)?,
+        ("SETEX", [key, ttl, value]) => RequestsEnum::Put {
+            key: to_key(key)?,
+            value: to_value(value),
+            ttl: Some(to_ttl(ttl)?),
+        },
+        ("DEL" | "UNLINK", [_, ..]) => RequestsEnum::DeleteMany { keys: to_keys(args)? },
+        ("EXISTS", [key]) => RequestsEnum::Exists { key: to_key(key)? },
+        ("TTL", [key]) => RequestsEnum::Ttl { key: to_key(key)? },
+        ("EXPIRE", [key, ttl]) => RequestsEnum::Expire { key: to_key(key)?, ttl: to_number(ttl)? },
+        ("EXPIREAT", [key, unix_ts]) => RequestsEnum::ExpireAt { key: to_key(key)?, unix_ts: to_number(unix_ts)? },
+        ("PERSIST", [key]) => RequestsEnum::Persist { key: to_key(key)? },
+        ("INCR", [key]) => RequestsEnum::Incr { key: to_key(key)?, ttl: None },
+        ("DECR", [key]) => RequestsEnum::Decr { key: to_key(key)?, ttl: None },
+        ("INCRBY", [key, delta]) => RequestsEnum::IncrBy { key: to_key(key)?, delta: to_number(delta)?, ttl: None },
+        ("DECRBY", [key, delta]) => RequestsEnum::IncrBy {
+            key: to_key(key)?,
+            delta: to_number::<i64>(delta)?.checked_neg().ok_or("ERR decrement would overflow")?,
+            ttl: None,
+        },
+        ("INCRBYFLOAT", [key, delta]) => RequestsEnum::IncrByFloat { key: to_key(key)?, delta: to_float(delta)?, ttl: None },
+        ("LPUSH", [key, values @ ..]) if !values.is_empty() => RequestsEnum::LPush {
+            key: to_key(key)?,
+            values: values.iter().map(|value| to_value(value)).collect(),
+            ttl: None,
+        },
+        ("RPUSH", [key, values @ ..]) if !values.is_empty() => RequestsEnum::RPush {
+            key: to_key(key)?,
+            values: values.iter().map(|value| to_value(value)).collect(),
+            ttl: None,
+        },
+        ("LPOP", [key]) => RequestsEnum::LPop { key: to_key(key)? },
+        ("RPOP", [key]) => RequestsEnum::RPop { key: to_key(key)? },
+        ("LRANGE", [key, start, stop]) => RequestsEnum::LRange { key: to_key(key)?, start: to_number(start)?, stop: to_number(stop)? },
+        ("LLEN", [key]) => RequestsEnum::LLen { key: to_key(key)? },
+        ("LTRIM", [key, start, stop]) => RequestsEnum::LTrim { key: to_key(key)?, start: to_number(start)?, stop: to_number(stop)? },
+        ("HSET", [key, fields @ ..]) if !fields.is_empty() && fields.len() % 2 == 0 => {
+            let fields = fields.chunks(2)
+                .map(|pair| Ok((to_key(&pair[0])?, to_value(&pair[1]))))
+                .collect::<Result<HashMap<_, _>, String>>()?;
+            RequestsEnum::HSet { key: to_key(key)?, fields, ttl: None }
+        }
+        ("HGET", [key, field]) => RequestsEnum::HGet { key: to_key(key)?, field: to_key(field)? },
+        ("HDEL", [key, fields @ ..]) if !fields.is_empty() => RequestsEnum::HDel { key: to_key(key)?, fields: to_keys(fields)? },
+        ("HGETALL", [key]) => RequestsEnum::HGetAll { key: to_key(key)? },
+        ("HINCRBY", [key, field, delta]) => RequestsEnum::HIncrBy {
+            key: to_key(key)?,
+            field: to_key(field)?,
+            delta: to_number(delta)?,
+            ttl: None,
+        },
+        ("HEXISTS", [key, field]) => RequestsEnum::HExists { key: to_key(key)?, field: to_key(field)? },
+        ("SADD", [key, members @ ..]) if !members.is_empty() => RequestsEnum::SAdd {
+            key: to_key(key)?,
+            members: members.iter().map(|member| to_value(member)).collect(),
+            ttl: None,
+        },
+        ("SREM", [key, members @ ..]) if !members.is_empty() => RequestsEnum::SRem {
+            key: to_key(key)?,
+            members: members.iter().map(|member| to_value(member)).collect(),
+        },
+        ("SMEMBERS", [key]) => RequestsEnum::SMembers { key: to_key(key)? },
+        ("SISMEMBER", [key, member]) => RequestsEnum::SIsMember { key: to_key(key)?, member: to_value(member) },
+        ("SCARD", [key]) => RequestsEnum::SCard { key: to_key(key)? },
+        // flags like NX or GT are not supported
+        ("ZADD", [key, members @ ..]) if !members.is_empty() && members.len() % 2 == 0 => {
+            let members = members.chunks(2)
+                .map(|pair| Ok(ScoredMember { member: to_value(&pair[1]), score: to_float(&pair[0])? }))
+                .collect::<Result<Vec<_>, String>>()?;
+            RequestsEnum::ZAdd { key: to_key(key)?, members, ttl: None }
+        }
+        ("ZRANGE", [key, start, stop, options @ ..]) => {
+            let request = RequestsEnum::ZRange { key: to_key(key)?, start: to_number(start)?, stop: to_number(stop)? };
+            return Ok(RespCommand::Request { request, with_scores: parse_with_scores(options)? });
+        }
+        ("ZRANGEBYSCORE", [key, min, max, options @ ..]) => {
+            let request = RequestsEnum::ZRangeByScore { key: to_key(key)?, min: to_float(min)?, max: to_float(max)? };
+            return Ok(RespCommand::Request { request, with_scores: parse_with_scores(options)? });
+        }
+        ("ZRANK", [key, member]) => RequestsEnum::ZRank { key: to_key(key)?, member: to_value(member) },
+        ("ZINCRBY", [key, delta, member]) => RequestsEnum::ZIncrBy {
+            key: to_key(key)?,
+            member: to_value(member),
+            delta: to_float(delta)?,
+            ttl: None,
+        },
+        _ => return Err(format!("ERR unknown command '{name}' or wrong number of arguments")),
+    };
+    Ok(RespCommand::Request { request, with_scores: false })
+}
+
+// SET key value [NX | XX] [EX seconds | PX milliseconds]
+fn parse_set(key: &[u8], value: &[u8], options: &[Vec<u8>]) -> Result<RequestsEnum, String> {
+    let key = to_key(key)?;
+    let value = to_value(value);
+    let mut ttl = None;
+    let mut only_if_absent = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"NX" if only_if_absent.is_none() => only_if_absent = Some(true),
+            b"XX" if only_if_absent.is_none() => only_if_absent = Some(false),
+            b"EX" if ttl.is_none() => {
+                ttl = Some(to_ttl(options.next().ok_or("ERR syntax error")?)?);
+            }
+            // TTL is kept in whole seconds, rounded up so keys never expire before they were asked to
+            b"PX" if ttl.is_none() => {
+                let millis = to_ttl(options.next().ok_or("ERR syntax error")?)?;
+                ttl = Some(millis.div_ceil(1000));
+            }
+            _ => return Err("ERR syntax error".to_string()),
+        }
+    }
+    Ok(match only_if_absent {
+        None => RequestsEnum::Put { key, value, ttl },
+        Some(true) => RequestsEnum::PutIfAbsent { key, value, ttl },
+        Some(false) => RequestsEnum::PutIfPresent { key, value, ttl },
+    })
+}
+
+fn parse_with_scores(options: &[Vec<u8>]) -> Result<bool, String> {
+    match options {
+        [] => Ok(false),
+        [option] if option.eq_ignore_ascii_case(b"WITHSCORES") => Ok(true),
+        _ => Err("ERR syntax error".to_string()),
+    }
+}
+
+fn reply(value: RespValue) -> RespCommand {
+    RespCommand::Reply(value)
+}
+
+fn ok() -> RespValue {
+    RespValue::SimpleString("OK".to_string())
+}
+
+// keys and hash fields are strings, values can be any bytes
+fn to_key(arg: &[u8]) -> Result<Key, String> {
+    String::from_utf8(arg.to_vec()).map_err(|_| "ERR keys and fields must be valid UTF-8".to_string())
+}
+
+fn to_keys(args: &[Vec<u8>]) -> Result<Vec<Key>, String> {
+    args.iter().map(|arg| to_key(arg)).collect()
+}
+
+fn to_value(arg: &[u8]) -> Value {
+    Value::from(arg.to_vec())
+}
+
+fn to_number<T: FromStr>(arg: &[u8]) -> Result<T, String> {
+    std::str::from_utf8(arg).ok()
+        .and_then(|number| number.parse().ok())
+        .ok_or_else(|| "ERR value is not an integer or out of range".to_string())
+}
+
+fn to_ttl(arg: &[u8]) -> Result<u64, String> {
+    match to_number(arg) {
+        Ok(0) | Err(_) => Err("ERR invalid expire time".to_string()),
+        Ok(ttl) => Ok(ttl),
+    }
+}
+
+// accepts `inf`, `+inf` and `-inf` too, for score ranges
+fn to_float(arg: &[u8]) -> Result<f64, String> {
+    std::str::from_utf8(arg).ok()
+        .and_then(|number| number.parse::<f64>().ok())
+        .filter(|number| !number.is_nan())
+        .ok_or_else(|| "ERR value is not a valid float".to_string())
+}
+
+pub fn hello_reply(protocol: Protocol) -> RespValue {
+    let proto = match protocol {
+        Protocol::Resp2 => 2,
+        Protocol::Resp3 => 3,
+    };
+    RespValue::Map(vec![
+        (bulk("server"), bulk("rusty-cache")),
+        (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+        (bulk("proto"), RespValue::Integer(proto)),
+        (bulk("mode"), bulk("cluster")),
+        (bulk("role"), bulk("master")),
+        (bulk("modules"), RespValue::Array(Vec::new())),
+    ])
+}
+
+fn bulk(string: &str) -> RespValue {
+    RespValue::Bulk(Value::from(string))
+}
+
+fn optional_bulk(value: Option<Value>) -> RespValue {
+    value.map(RespValue::Bulk).unwrap_or(RespValue::Null)
+}
+
+pub fn to_reply(response: ReqResponseEnum, with_scores: bool, protocol: Protocol) -> RespValue {
+    match response {
+        ReqResponseEnum::Put | ReqResponseEnum::LTrim | ReqResponseEnum::LeftCluster => ok(),
+        ReqResponseEnum::Get { value, .. }
+        | ReqResponseEnum::Pop { value }
+        | ReqResponseEnum::HGet { value } => optional_bulk(value),
+        // SET with NX or XX
+        ReqResponseEnum::ConditionalPut { stored, .. } => if stored { ok() } else { RespValue::Null },
+        ReqResponseEnum::Exists { exists }
+        | ReqResponseEnum::HExists { exists }
+        | ReqResponseEnum::SIsMember { is_member: exists } => RespValue::Integer(exists as i64),
+        ReqResponseEnum::Delete { deleted } => RespValue::Integer(deleted as i64),
+        ReqResponseEnum::DeleteMany { deleted } => RespValue::Integer(deleted as i64),
+        ReqResponseEnum::Ttl { exists, ttl } => RespValue::Integer(match (exists, ttl) {
+            (false, _) => -2,
+            (true, None) => -1,
+            (true, Some(ttl)) => ttl as i64,
+        }),
+        ReqResponseEnum::Expire { updated }
+        | ReqResponseEnum::Persist { persisted: updated } => RespValue::Integer(updated as i64),
+        ReqResponseEnum::Incr { value }
+        | ReqResponseEnum::HIncrBy { value } => RespValue::Integer(value),
+        ReqResponseEnum::IncrByFloat { value } => RespValue::Bulk(Value::from(value.to_string())),
+        // unsigned counters can be out of range of RESP integers
+        ReqResponseEnum::IncrExisting { value } => value.map(|value| RespValue::Bulk(Value::from(value.to_string()))).unwrap_or(RespValue::Null),
+        ReqResponseEnum::Push { len }
+        | ReqResponseEnum::LLen { len }
+        | ReqResponseEnum::HSet { added: len }
+        | ReqResponseEnum::HDel { deleted: len }
+        | ReqResponseEnum::SAdd { added: len }
+        | ReqResponseEnum::SRem { removed: len }
+        | ReqResponseEnum::SCard { count: len }
+        | ReqResponseEnum::ZAdd { added: len } => RespValue::Integer(len as i64),
+        ReqResponseEnum::LRange { values } => RespValue::Array(values.into_iter().map(RespValue::Bulk).collect()),
+        ReqResponseEnum::HGetAll { fields } => RespValue::Map(
+            fields.into_iter()
+                .map(|(field, value)| (RespValue::Bulk(Value::from(field)), RespValue::Bulk(value)))
+                .collect()
+        ),
+        ReqResponseEnum::SMembers { members } => RespValue::Set(members.into_iter().map(RespValue::Bulk).collect()),
+        ReqResponseEnum::ZRange { members } => {
+            let members = members.into_iter();
+            RespValue::Array(match (with_scores, protocol) {
+                (false, _) => members.map(|scored| RespValue::Bulk(scored.member)).collect(),
+                // RESP3 pairs each member with its score, RESP2 puts them one after another
+                (true, Protocol::Resp3) => members
+                    .map(|scored| RespValue::Array(vec![RespValue::Bulk(scored.member), RespValue::Double(scored.score)]))
+                    .collect(),
+                (true, Protocol::Resp2) => members
+                    .flat_map(|scored| [RespValue::Bulk(scored.member), RespValue::Double(scored.score)])
+                    .collect(),
+            })
+        }
+        ReqResponseEnum::ZRank { rank } => rank.map(|rank| RespValue::Integer(rank as i64)).unwrap_or(RespValue::Null),
+        ReqResponseEnum::ZIncrBy { score } => RespValue::Double(score),
+        ReqResponseEnum::Stats { node_id, stats } => RespValue::Bulk(Value::from(format!(
+            "# Stats\r\nnode_id:{node_id}\r\nentries:{}\r\nused_bytes:{}\r\nevictions:{}\r\nexpirations:{}\r\n",
+            stats.entries, stats.used_bytes, stats.evictions, stats.expirations
+        ))),
+        ReqResponseEnum::Error { error } => RespValue::Error(match error {
+            CacheError::NotANumber => "ERR value is not a valid number".to_string(),
+            CacheError::Overflow => "ERR increment or decrement would overflow".to_string(),
+            CacheError::WrongType => "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+        }),
+        ReqResponseEnum::ErrorProcessingCommand {} => RespValue::Error("ERR couldn't process the command".to_string()),
+    }
+}
+
+impl RespValue {
+    pub fn write<W: Write>(&self, writer: &mut W, protocol: Protocol) -> io::Result<()> {
+        let resp3 = protocol == Protocol::Resp3;
+        match self {
+            RespValue::SimpleString(string) => write!(writer, "+{string}\r\n"),
+            RespValue::Error(error) => write!(writer, "-{error}\r\n"),
+            RespValue::Integer(integer) => write!(writer, ":{integer}\r\n"),
+            RespValue::Bulk(value) => {
+                write!(writer, "${}\r\n", value.len())?;
+                writer.write_all(value)?;
+                writer.write_all(b"\r\n")
+            }
+            RespValue::Null if resp3 => writer.write_all(b"_\r\n"),
+            RespValue::Null => writer.write_all(b"$-1\r\n"),
+            RespValue::Double(double) if resp3 => write!(writer, ",{double}\r\n"),
+            RespValue::Double(double) => RespValue::Bulk(Value::from(double.to_string())).write(writer, protocol),
+            RespValue::Array(items) => write_items(writer, '*', items, protocol),
+            RespValue::Set(items) => write_items(writer, if resp3 { '~' } else { '*' }, items, protocol),
+            RespValue::Map(entries) => {
+                // RESP2 has no maps, keys and values go one after another in an array
+                if resp3 {
+                    write!(writer, "%{}\r\n", entries.len())?;
+                } else {
+                    write!(writer, "*{}\r\n", entries.len() * 2)?;
+                }
+                for (key, value) in entries {
+                    key.write(writer, protocol)?;
+                    value.write(writer, protocol)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn write_items<W: Write>(writer: &mut W, prefix: char, items: &[RespValue], protocol: Protocol) -> io::Result<()> {
+    write!(writer, "{prefix}{}\r\n", items.len())?;
+    for item in items {
+        item.write(writer, protocol)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::test_util::block_on;
+    use super::*;
+
+    fn read(mut bytes: &[u8]) -> io::Result<Option<Vec<Vec<u8>>>> {
+        block_on(read_command(&mut bytes))
+    }
+
+    fn read_error(bytes: &[u8]) -> io::ErrorKind {
+        read(bytes).unwrap_err().kind()
+    }
+
+    fn args(args: &[&str]) -> Vec<Vec<u8>> {
+        args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    fn error_reply(command: RespCommand) -> Option<String> {
+        match command {
+            RespCommand::Reply(RespValue::Error(error)) => Some(error),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn reads_commands_one_after_another() {
+        let mut bytes: &[u8] = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*1\r\n$4\r\nPING\r\n";
+        block_on(async {
+            assert_eq!(read_command(&mut bytes).await.unwrap(), Some(args(&["GET", "k"])));
+            assert_eq!(read_command(&mut bytes).await.unwrap(), Some(args(&["PING"])));
+            assert_eq!(read_command(&mut bytes).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        assert_eq!(read_error(b"*1\n$4\r\nPING\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(read_error(b"PING\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(read_error(b"*1\r\n+PING\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(read_error(b"*x\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(read_error(b"*-1\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(read_error(format!("*1\r\n${}\r\n", MAX_BULK_LEN + 1).as_bytes()), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn header_line_is_limited() {
+        let line = format!("*{}\r\n", "0".repeat(MAX_HEADER_LEN as usize));
+        assert_eq!(read_error(line.as_bytes()), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn bulk_string_must_end_with_crlf() {
+        assert_eq!(read_error(b"*1\r\n$4\r\nPINGxx"), io::ErrorKind::InvalidData);
+        assert_eq!(read_error(b"*1\r\n$4\r\nPI"), io::ErrorKind::UnexpectedEof);
+        assert_eq!(read_error(b"*2\r\n$4\r\nPING\r\n"), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn command_length_is_limited() {
+        let mut bytes = b"*5\r\n".to_vec();
+        for _ in 0..MAX_COMMAND_LEN / MAX_BULK_LEN {
+            bytes.extend_from_slice(format!("${MAX_BULK_LEN}\r\n").as_bytes());
+            bytes.resize(bytes.len() + MAX_BULK_LEN, b'a');
+            bytes.extend_from_slice(b"\r\n");
+        }
+        // the last argument is rejected by its header, before its contents are read
+        assert_eq!(read_error(&bytes), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn set_rounds_px_up_to_whole_seconds() {
+        for (millis, seconds) in [("2000", 2), ("1500", 2), ("1", 1)] {
+            match parse_command(args(&["SET", "k", "v", "PX", millis])) {
+                RespCommand::Request { request: RequestsEnum::Put { ttl, .. }, .. } => assert_eq!(ttl, Some(seconds)),
+                _ => panic!("expected a put"),
+            }
+        }
+    }
+
+    #[test]
+    fn set_options_are_checked() {
+        assert!(error_reply(parse_command(args(&["SET", "k", "v", "EX", "0"]))).is_some());
+        assert!(error_reply(parse_command(args(&["SET", "k", "v", "EX"]))).is_some());
+        assert!(error_reply(parse_command(args(&["SET", "k", "v", "NX", "XX"]))).is_some());
+        assert!(error_reply(parse_command(args(&["SET", "k", "v", "EX", "1", "PX", "1000"]))).is_some());
+    }
+
+    #[test]
+    fn zadd_rejects_nan_scores() {
+        let error = error_reply(parse_command(args(&["ZADD", "k", "nan", "m"])));
+        assert_eq!(error.as_deref(), Some("ERR value is not a valid float"));
+    }
+}
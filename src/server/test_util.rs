@@ -0,0 +1,6 @@
+use std::future::Future;
+
+// unit tests run async readers over byte slices, without the event loop of the server
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(future)
+}
@@ -1,79 +1,314 @@
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::ops::Add;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use log::{info, warn};
-use crate::server::cache::Cache;
+use crate::server::cache::{Cache, CacheError, Key, PutCondition};
+use crate::server::cluster_command_processing;
 use crate::server::cluster::{Cluster, NodeId};
 use crate::server::requests::{ReqResponseEnum, RequestsEnum};
 
 pub fn process_client_request(request: RequestsEnum,
-                              cache: &mut Cache,
-                              cluster: &mut Cluster,
+                              cache: &Cache,
+                              cluster: &Cluster,
+) -> ReqResponseEnum {
+    process_request(request, cache, cluster, false)
+}
+
+/// Executes request another node has forwarded here, as this node is responsible for its key.
+/// It isn't forwarded again if cluster state here differs, so requests never go around in circles,
+/// the client gets an error instead.
+pub fn process_forwarded_request(request: RequestsEnum,
+                                 cache: &Cache,
+                                 cluster: &Cluster,
+) -> ReqResponseEnum {
+    process_request(request, cache, cluster, true)
+}
+
+fn process_request(request: RequestsEnum,
+                   cache: &Cache,
+                   cluster: &Cluster,
+                   forwarded: bool,
 ) -> ReqResponseEnum {
     match request.clone() {
-        RequestsEnum::Put { key, value, ttl } => {
-            let is_key_local = cluster.is_key_local(&key);
-            if is_key_local {
-                cache.put(&key, &value, ttl);
-                ReqResponseEnum::Put {}
+        RequestsEnum::Put { key, .. }
+        | RequestsEnum::PutIfAbsent { key, .. }
+        | RequestsEnum::PutIfPresent { key, .. }
+        | RequestsEnum::Cas { key, .. }
+        | RequestsEnum::Delete { key }
+        | RequestsEnum::Expire { key, .. }
+        | RequestsEnum::ExpireAt { key, .. }
+        | RequestsEnum::Persist { key }
+        | RequestsEnum::Incr { key, .. }
+        | RequestsEnum::Decr { key, .. }
+        | RequestsEnum::IncrBy { key, .. }
+        | RequestsEnum::IncrExisting { key, .. }
+        | RequestsEnum::IncrByFloat { key, .. }
+        | RequestsEnum::LPush { key, .. }
+        | RequestsEnum::RPush { key, .. }
+        | RequestsEnum::LPop { key }
+        | RequestsEnum::RPop { key }
+        | RequestsEnum::LTrim { key, .. }
+        | RequestsEnum::HSet { key, .. }
+        | RequestsEnum::HDel { key, .. }
+        | RequestsEnum::HIncrBy { key, .. }
+        | RequestsEnum::SAdd { key, .. }
+        | RequestsEnum::SRem { key, .. }
+        | RequestsEnum::ZAdd { key, .. }
+        | RequestsEnum::ZIncrBy { key, .. } => {
+            if cluster.is_key_local(&key) {
+                // otherwise a write applied later could get a lower offset, and replicas would end up with a different value
+                let _bucket_writes = cluster.lock_bucket_writes(&key);
+                let response = execute_request(request.clone(), cache);
+                if let Some(replicated_request) = get_replicated_write(request, &response) {
+                    cluster.replicate_request(&key, &replicated_request);
+                }
+                response
+            } else if forwarded {
+                reject_forwarded_request(cluster, &key)
             } else {
                 let target_node = cluster.get_node_for_key(&key);
-                redirect_request(cluster, target_node, request.clone())
+                redirect_request(cluster, target_node, request)
             }
         }
-        RequestsEnum::Get { key } => {
-            let is_key_local = cluster.is_key_local(&key);
-            if is_key_local {
-                let value = cache.get(&key);
-                ReqResponseEnum::Get {
-                    key,
-                    value,
-                }
+        RequestsEnum::Get { key }
+        | RequestsEnum::Exists { key }
+        | RequestsEnum::Ttl { key }
+        | RequestsEnum::LRange { key, .. }
+        | RequestsEnum::LLen { key }
+        | RequestsEnum::HGet { key, .. }
+        | RequestsEnum::HGetAll { key }
+        | RequestsEnum::HExists { key, .. }
+        | RequestsEnum::SMembers { key }
+        | RequestsEnum::SIsMember { key, .. }
+        | RequestsEnum::SCard { key }
+        | RequestsEnum::ZRange { key, .. }
+        | RequestsEnum::ZRangeByScore { key, .. }
+        | RequestsEnum::ZRank { key, .. } => {
+            if cluster.can_read_locally(&key) {
+                execute_request(request, cache)
+            } else if forwarded {
+                reject_forwarded_request(cluster, &key)
             } else {
                 let target_node = cluster.get_node_for_key(&key);
-                redirect_request(cluster, target_node, request.clone())
+                redirect_request(cluster, target_node, request)
             }
         }
-        RequestsEnum::Exists { key } => {
-            let is_key_local = cluster.is_key_local(&key);
-            if is_key_local {
-                let exists = cache.exists(&key);
-                ReqResponseEnum::Exists { exists }
-            } else {
-                let target_node = cluster.get_node_for_key(&key);
-                redirect_request(cluster, target_node, request.clone())
+        RequestsEnum::DeleteMany { keys } => {
+            // keys can belong to different nodes, so each one is routed separately
+            let deleted = keys.into_iter()
+                .map(|key| process_request(RequestsEnum::Delete { key }, cache, cluster, forwarded))
+                .filter(|response| matches!(response, ReqResponseEnum::Delete { deleted: true }))
+                .count();
+            ReqResponseEnum::DeleteMany { deleted: deleted as u64 }
+        }
+        RequestsEnum::Stats => {
+            ReqResponseEnum::Stats {
+                node_id: cluster.self_node_id.clone(),
+                stats: cache.get_stats(),
             }
         }
-        RequestsEnum::Exit {} => {
+        RequestsEnum::LeaveCluster => {
+            cluster_command_processing::leave_cluster(cluster, cache);
+            ReqResponseEnum::LeftCluster
+        }
+        RequestsEnum::Exit => {
             warn!("Received EXIT command. Wrapping up.");
             panic!("Received EXIT command");
         }
     }
 }
 
-// TODO: this function probably shouldn't be here
-fn redirect_request(cluster: &mut Cluster, target_node: NodeId, request: RequestsEnum) -> ReqResponseEnum {
-    let connection_or_none = cluster.get_node_connection(&target_node);
-    match connection_or_none {
-        None => {
-            warn!("Received EXIT command. Wrapping up.");
+/// Executes single-key request against local cache, without any routing.
+/// Used both for requests this node is responsible for, and for writes replicated from bucket primary.
+pub fn execute_request(request: RequestsEnum, cache: &Cache) -> ReqResponseEnum {
+    match request {
+        RequestsEnum::Put { key, value, ttl } => {
+            cache.put(&key, &value, ttl);
+            ReqResponseEnum::Put {}
+        }
+        RequestsEnum::Get { key } => {
+            match cache.get(&key) {
+                Ok(versioned) => ReqResponseEnum::Get {
+                    key,
+                    value: versioned.as_ref().map(|versioned| versioned.value.clone()),
+                    version: versioned.map(|versioned| versioned.version),
+                },
+                Err(error) => ReqResponseEnum::Error { error },
+            }
+        }
+        RequestsEnum::PutIfAbsent { key, value, ttl } => {
+            conditional_put_response(cache.put_if(&key, &value, ttl, PutCondition::Absent))
+        }
+        RequestsEnum::PutIfPresent { key, value, ttl } => {
+            conditional_put_response(cache.put_if(&key, &value, ttl, PutCondition::Present))
+        }
+        RequestsEnum::Cas { key, value, ttl, version } => {
+            conditional_put_response(cache.put_if(&key, &value, ttl, PutCondition::Version(version)))
+        }
+        RequestsEnum::Exists { key } => {
+            let exists = cache.exists(&key);
+            ReqResponseEnum::Exists { exists }
+        }
+        RequestsEnum::Delete { key } => {
+            let deleted = cache.remove(&key);
+            ReqResponseEnum::Delete { deleted }
+        }
+        RequestsEnum::Ttl { key } => {
+            match cache.get_ttl(&key) {
+                Some(ttl) => ReqResponseEnum::Ttl { exists: true, ttl },
+                None => ReqResponseEnum::Ttl { exists: false, ttl: None },
+            }
+        }
+        RequestsEnum::Expire { key, ttl } => {
+            let updated = cache.expire(&key, SystemTime::now().add(Duration::from_secs(ttl)));
+            ReqResponseEnum::Expire { updated }
+        }
+        RequestsEnum::ExpireAt { key, unix_ts } => {
+            let updated = cache.expire(&key, UNIX_EPOCH.add(Duration::from_secs(unix_ts)));
+            ReqResponseEnum::Expire { updated }
+        }
+        RequestsEnum::Persist { key } => {
+            let persisted = cache.persist(&key);
+            ReqResponseEnum::Persist { persisted }
+        }
+        RequestsEnum::Incr { key, ttl } => {
+            to_response(cache.incr_by(&key, 1, ttl), |value| ReqResponseEnum::Incr { value })
+        }
+        RequestsEnum::Decr { key, ttl } => {
+            to_response(cache.incr_by(&key, -1, ttl), |value| ReqResponseEnum::Incr { value })
+        }
+        RequestsEnum::IncrBy { key, delta, ttl } => {
+            to_response(cache.incr_by(&key, delta, ttl), |value| ReqResponseEnum::Incr { value })
+        }
+        RequestsEnum::IncrExisting { key, delta, decrement } => {
+            to_response(cache.incr_existing(&key, delta, decrement), |value| ReqResponseEnum::IncrExisting { value })
+        }
+        RequestsEnum::IncrByFloat { key, delta, ttl } => {
+            to_response(cache.incr_by_float(&key, delta, ttl), |value| ReqResponseEnum::IncrByFloat { value })
+        }
+        RequestsEnum::LPush { key, values, ttl } => {
+            to_response(cache.push(&key, values, true, ttl), |len| ReqResponseEnum::Push { len })
+        }
+        RequestsEnum::RPush { key, values, ttl } => {
+            to_response(cache.push(&key, values, false, ttl), |len| ReqResponseEnum::Push { len })
+        }
+        RequestsEnum::LPop { key } => {
+            to_response(cache.pop(&key, true), |value| ReqResponseEnum::Pop { value })
+        }
+        RequestsEnum::RPop { key } => {
+            to_response(cache.pop(&key, false), |value| ReqResponseEnum::Pop { value })
+        }
+        RequestsEnum::LRange { key, start, stop } => {
+            to_response(cache.list_range(&key, start, stop), |values| ReqResponseEnum::LRange { values })
+        }
+        RequestsEnum::LLen { key } => {
+            to_response(cache.list_len(&key), |len| ReqResponseEnum::LLen { len })
+        }
+        RequestsEnum::LTrim { key, start, stop } => {
+            to_response(cache.list_trim(&key, start, stop), |_| ReqResponseEnum::LTrim)
+        }
+        RequestsEnum::HSet { key, fields, ttl } => {
+            to_response(cache.hash_set(&key, fields, ttl), |added| ReqResponseEnum::HSet { added })
+        }
+        RequestsEnum::HGet { key, field } => {
+            to_response(cache.hash_get(&key, &field), |value| ReqResponseEnum::HGet { value })
+        }
+        RequestsEnum::HDel { key, fields } => {
+            to_response(cache.hash_delete(&key, &fields), |deleted| ReqResponseEnum::HDel { deleted })
+        }
+        RequestsEnum::HGetAll { key } => {
+            to_response(cache.hash_get_all(&key), |fields| ReqResponseEnum::HGetAll { fields })
+        }
+        RequestsEnum::HIncrBy { key, field, delta, ttl } => {
+            to_response(cache.hash_incr_by(&key, &field, delta, ttl), |value| ReqResponseEnum::HIncrBy { value })
+        }
+        RequestsEnum::HExists { key, field } => {
+            to_response(cache.hash_exists(&key, &field), |exists| ReqResponseEnum::HExists { exists })
+        }
+        RequestsEnum::SAdd { key, members, ttl } => {
+            to_response(cache.set_add(&key, members, ttl), |added| ReqResponseEnum::SAdd { added })
+        }
+        RequestsEnum::SRem { key, members } => {
+            to_response(cache.set_remove(&key, &members), |removed| ReqResponseEnum::SRem { removed })
+        }
+        RequestsEnum::SMembers { key } => {
+            to_response(cache.set_members(&key), |members| ReqResponseEnum::SMembers { members })
+        }
+        RequestsEnum::SIsMember { key, member } => {
+            to_response(cache.set_is_member(&key, &member), |is_member| ReqResponseEnum::SIsMember { is_member })
+        }
+        RequestsEnum::SCard { key } => {
+            to_response(cache.set_len(&key), |count| ReqResponseEnum::SCard { count })
+        }
+        RequestsEnum::ZAdd { key, members, ttl } => {
+            to_response(cache.sorted_set_add(&key, members, ttl), |added| ReqResponseEnum::ZAdd { added })
+        }
+        RequestsEnum::ZRange { key, start, stop } => {
+            to_response(cache.sorted_set_range(&key, start, stop), |members| ReqResponseEnum::ZRange { members })
+        }
+        RequestsEnum::ZRangeByScore { key, min, max } => {
+            to_response(cache.sorted_set_range_by_score(&key, min, max), |members| ReqResponseEnum::ZRange { members })
+        }
+        RequestsEnum::ZRank { key, member } => {
+            to_response(cache.sorted_set_rank(&key, &member), |rank| ReqResponseEnum::ZRank { rank })
+        }
+        RequestsEnum::ZIncrBy { key, member, delta, ttl } => {
+            to_response(cache.sorted_set_incr_by(&key, &member, delta, ttl), |score| ReqResponseEnum::ZIncrBy { score })
+        }
+        _ => {
+            warn!("Request {request:?} can't be executed locally");
             ReqResponseEnum::ErrorProcessingCommand {}
         }
-        Some(arc_conn) => {
-            let stream = arc_conn.lock().unwrap();
+    }
+}
 
-            let mut reader = BufReader::new(stream.try_clone().unwrap());
-            let mut writer = BufWriter::new(stream.try_clone().unwrap());
+// replicas apply conditional writes unconditionally, since versions of their values differ from ones on primary,
+// and failed conditional writes are not replicated at all
+fn get_replicated_write(request: RequestsEnum, response: &ReqResponseEnum) -> Option<RequestsEnum> {
+    match request {
+        RequestsEnum::PutIfAbsent { key, value, ttl }
+        | RequestsEnum::PutIfPresent { key, value, ttl }
+        | RequestsEnum::Cas { key, value, ttl, .. } => {
+            match response {
+                ReqResponseEnum::ConditionalPut { stored: true, .. } => Some(RequestsEnum::Put { key, value, ttl }),
+                _ => None,
+            }
+        }
+        _ => Some(request),
+    }
+}
 
-            let mut request_str = serde_json::to_string(&request).unwrap();
-            request_str.push('\n');
+fn conditional_put_response(version: Option<u64>) -> ReqResponseEnum {
+    ReqResponseEnum::ConditionalPut { stored: version.is_some(), version }
+}
 
-            writer.write_all(request_str.as_bytes()).unwrap();
-            writer.flush().unwrap();
+fn to_response<T, F>(result: Result<T, CacheError>, response: F) -> ReqResponseEnum
+where
+    F: FnOnce(T) -> ReqResponseEnum,
+{
+    match result {
+        Ok(value) => response(value),
+        Err(error) => ReqResponseEnum::Error { error },
+    }
+}
 
-            let mut s = String::new();
-            reader.read_line(&mut s).unwrap();
-            info!("Received response: {s}");
-            let response: ReqResponseEnum = serde_json::from_str(&s).unwrap();
+// TODO: this function probably shouldn't be here
+// node which forwarded the request had different cluster state, e.g. the bucket has moved in the meantime
+fn reject_forwarded_request(cluster: &Cluster, key: &Key) -> ReqResponseEnum {
+    let bucket_id = cluster.get_bucket_for_key(key);
+    warn!("Received forwarded request for bucket {bucket_id}, which is served by {}", cluster.get_node_for_key(key));
+    ReqResponseEnum::ErrorProcessingCommand {}
+}
+
+fn redirect_request(cluster: &Cluster, target_node: NodeId, request: RequestsEnum) -> ReqResponseEnum {
+    // target node may be down, until failure detection removes it we answer with an error
+    match cluster.forward_request(&target_node, request) {
+        Ok(response) => {
+            info!("Received response: {response:?}");
             response
         }
+        Err(e) => {
+            warn!("Couldn't redirect request to {target_node}: {e}");
+            ReqResponseEnum::ErrorProcessingCommand {}
+        }
     }
-}
\ No newline at end of file
+}
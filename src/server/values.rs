@@ -0,0 +1,586 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::ops::Deref;
+use serde::de::{Error, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::server::cache::CacheError;
+
+pub type Field = String;
+
+/// Binary-safe value. Binary formats carry it as raw bytes. In JSON it's a string when it's valid UTF-8,
+/// and `{"hex": "..."}` otherwise, both forms are accepted from clients.
+#[derive(Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Value(Vec<u8>);
+
+impl Value {
+    pub fn new() -> Value {
+        Value(Vec::new())
+    }
+
+    // none if value is not valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+}
+
+impl Deref for Value {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(bytes: Vec<u8>) -> Value {
+        Value(bytes)
+    }
+}
+
+impl From<String> for Value {
+    fn from(string: String) -> Value {
+        Value(string.into_bytes())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(string: &str) -> Value {
+        Value(string.as_bytes().to_vec())
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.as_str() {
+            Some(string) => write!(f, "{string:?}"),
+            None => write!(f, "hex:{}", hex::encode(&self.0)),
+        }
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return serializer.serialize_bytes(&self.0);
+        }
+        match self.as_str() {
+            Some(string) => serializer.serialize_str(string),
+            None => {
+                let mut hex_value = HashMap::new();
+                hex_value.insert("hex", hex::encode(&self.0));
+                hex_value.serialize(serializer)
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string, bytes, or {\"hex\": \"...\"}")
+    }
+
+    fn visit_str<E: Error>(self, string: &str) -> Result<Value, E> {
+        Ok(Value::from(string))
+    }
+
+    fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<Value, E> {
+        Ok(Value(bytes.to_vec()))
+    }
+
+    fn visit_byte_buf<E: Error>(self, bytes: Vec<u8>) -> Result<Value, E> {
+        Ok(Value(bytes))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element::<u8>()? {
+            bytes.push(byte);
+        }
+        Ok(Value(bytes))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        match map.next_entry::<String, String>()? {
+            Some((encoding, encoded)) if encoding == "hex" => {
+                hex::decode(encoded).map(Value).map_err(A::Error::custom)
+            }
+            _ => Err(A::Error::custom("expected {\"hex\": \"...\"}")),
+        }
+    }
+}
+
+/// Value stored under a key. Collections keep track of their size in bytes,
+/// so cache can account for them without walking all of their elements.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StoredValue {
+    String(Value),
+    List(List),
+    Hash(Hash),
+    Set(Set),
+    SortedSet(SortedSet),
+}
+
+/// Member of a sorted set together with its score.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScoredMember {
+    pub member: Value,
+    pub score: f64,
+}
+
+impl StoredValue {
+    pub fn size(&self) -> usize {
+        match self {
+            StoredValue::String(value) => value.len(),
+            StoredValue::List(list) => list.bytes,
+            StoredValue::Hash(hash) => hash.bytes,
+            StoredValue::Set(set) => set.bytes,
+            StoredValue::SortedSet(sorted_set) => sorted_set.bytes,
+        }
+    }
+
+    // collections are removed once their last element is gone, same as in Redis
+    pub fn is_empty(&self) -> bool {
+        match self {
+            StoredValue::String(_) => false,
+            StoredValue::List(list) => list.items.is_empty(),
+            StoredValue::Hash(hash) => hash.fields.is_empty(),
+            StoredValue::Set(set) => set.members.is_empty(),
+            StoredValue::SortedSet(sorted_set) => sorted_set.scores.is_empty(),
+        }
+    }
+
+    pub fn as_string(&self) -> Result<&Value, CacheError> {
+        match self {
+            StoredValue::String(value) => Ok(value),
+            _ => Err(CacheError::WrongType),
+        }
+    }
+
+    pub fn as_string_mut(&mut self) -> Result<&mut Value, CacheError> {
+        match self {
+            StoredValue::String(value) => Ok(value),
+            _ => Err(CacheError::WrongType),
+        }
+    }
+
+    pub fn as_list(&self) -> Result<&List, CacheError> {
+        match self {
+            StoredValue::List(list) => Ok(list),
+            _ => Err(CacheError::WrongType),
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Result<&mut List, CacheError> {
+        match self {
+            StoredValue::List(list) => Ok(list),
+            _ => Err(CacheError::WrongType),
+        }
+    }
+
+    pub fn as_hash(&self) -> Result<&Hash, CacheError> {
+        match self {
+            StoredValue::Hash(hash) => Ok(hash),
+            _ => Err(CacheError::WrongType),
+        }
+    }
+
+    pub fn as_hash_mut(&mut self) -> Result<&mut Hash, CacheError> {
+        match self {
+            StoredValue::Hash(hash) => Ok(hash),
+            _ => Err(CacheError::WrongType),
+        }
+    }
+
+    pub fn as_set(&self) -> Result<&Set, CacheError> {
+        match self {
+            StoredValue::Set(set) => Ok(set),
+            _ => Err(CacheError::WrongType),
+        }
+    }
+
+    pub fn as_set_mut(&mut self) -> Result<&mut Set, CacheError> {
+        match self {
+            StoredValue::Set(set) => Ok(set),
+            _ => Err(CacheError::WrongType),
+        }
+    }
+
+    pub fn as_sorted_set(&self) -> Result<&SortedSet, CacheError> {
+        match self {
+            StoredValue::SortedSet(sorted_set) => Ok(sorted_set),
+            _ => Err(CacheError::WrongType),
+        }
+    }
+
+    pub fn as_sorted_set_mut(&mut self) -> Result<&mut SortedSet, CacheError> {
+        match self {
+            StoredValue::SortedSet(sorted_set) => Ok(sorted_set),
+            _ => Err(CacheError::WrongType),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct List {
+    items: VecDeque<Value>,
+    // total size of all items
+    bytes: usize,
+}
+
+impl List {
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn push_front(&mut self, value: Value) {
+        self.bytes += value.len();
+        self.items.push_front(value);
+    }
+
+    pub fn push_back(&mut self, value: Value) {
+        self.bytes += value.len();
+        self.items.push_back(value);
+    }
+
+    pub fn pop_front(&mut self) -> Option<Value> {
+        let value = self.items.pop_front()?;
+        self.bytes -= value.len();
+        Some(value)
+    }
+
+    pub fn pop_back(&mut self) -> Option<Value> {
+        let value = self.items.pop_back()?;
+        self.bytes -= value.len();
+        Some(value)
+    }
+
+    pub fn range(&self, start: i64, stop: i64) -> Vec<Value> {
+        match normalize_range(self.items.len(), start, stop) {
+            Some((start, stop)) => self.items.range(start..=stop).cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // keeps only the items in the range
+    pub fn trim(&mut self, start: i64, stop: i64) {
+        match normalize_range(self.items.len(), start, stop) {
+            Some((start, stop)) => {
+                self.items.truncate(stop + 1);
+                self.items.drain(..start);
+                self.bytes = self.items.iter().map(|value| value.len()).sum();
+            }
+            None => {
+                self.items.clear();
+                self.bytes = 0;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Hash {
+    fields: HashMap<Field, Value>,
+    // total size of all fields and their values
+    bytes: usize,
+}
+
+impl Hash {
+    pub fn get(&self, field: &Field) -> Option<&Value> {
+        self.fields.get(field)
+    }
+
+    pub fn contains(&self, field: &Field) -> bool {
+        self.fields.contains_key(field)
+    }
+
+    pub fn get_all(&self) -> HashMap<Field, Value> {
+        self.fields.clone()
+    }
+
+    // returns true if the field is new
+    pub fn set(&mut self, field: Field, value: Value) -> bool {
+        let field_len = field.len();
+        self.bytes += value.len();
+        match self.fields.insert(field, value) {
+            Some(previous_value) => {
+                self.bytes -= previous_value.len();
+                false
+            }
+            None => {
+                self.bytes += field_len;
+                true
+            }
+        }
+    }
+
+    // returns true if the field existed
+    pub fn delete(&mut self, field: &Field) -> bool {
+        match self.fields.remove(field) {
+            Some(value) => {
+                self.bytes -= field.len() + value.len();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Set {
+    members: HashSet<Value>,
+    // total size of all members
+    bytes: usize,
+}
+
+impl Set {
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn contains(&self, member: &Value) -> bool {
+        self.members.contains(member)
+    }
+
+    pub fn members(&self) -> Vec<Value> {
+        self.members.iter().cloned().collect()
+    }
+
+    // returns true if the member is new
+    pub fn add(&mut self, member: Value) -> bool {
+        let member_len = member.len();
+        let added = self.members.insert(member);
+        if added {
+            self.bytes += member_len;
+        }
+        added
+    }
+
+    // returns true if the member existed
+    pub fn remove(&mut self, member: &Value) -> bool {
+        let removed = self.members.remove(member);
+        if removed {
+            self.bytes -= member.len();
+        }
+        removed
+    }
+}
+
+// f64 ordered with `total_cmp`, so it can be used in ordered collections
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Score(f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+// only scores of members are sent, the rest is rebuilt from them on the other side,
+// so a set received from another node can't have its order disagree with its scores
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(from = "HashMap<Value, f64>")]
+pub struct SortedSet {
+    scores: HashMap<Value, f64>,
+    // members ordered by score, then by member itself, same as in Redis.
+    // rank and range by position walk the set, so they take linear time
+    ordered: BTreeSet<(Score, Value)>,
+    // total size of all members and their scores
+    bytes: usize,
+}
+
+impl SortedSet {
+    // returns true if the member is new, otherwise updates its score
+    pub fn add(&mut self, member: Value, score: f64) -> bool {
+        // -0.0 is stored as 0.0, as `Score` orders it before 0.0, and ranges starting at 0 would miss it
+        let score = if score == 0.0 { 0.0 } else { score };
+        match self.scores.insert(member.clone(), score) {
+            Some(previous_score) => {
+                self.ordered.remove(&(Score(previous_score), member.clone()));
+                self.ordered.insert((Score(score), member));
+                false
+            }
+            None => {
+                self.bytes += member.len() + size_of::<f64>();
+                self.ordered.insert((Score(score), member));
+                true
+            }
+        }
+    }
+
+    // returns new score of the member, missing member is added with score 0
+    pub fn incr_by(&mut self, member: Value, delta: f64) -> Result<f64, CacheError> {
+        let score = self.scores.get(&member).copied().unwrap_or(0.0) + delta;
+        if score.is_nan() {
+            return Err(CacheError::NotANumber);
+        }
+        self.add(member, score);
+        Ok(score)
+    }
+
+    // position of the member, starting from the lowest score
+    pub fn rank(&self, member: &Value) -> Option<usize> {
+        let score = self.scores.get(member)?;
+        Some(self.ordered.range(..(Score(*score), member.clone())).count())
+    }
+
+    pub fn range(&self, start: i64, stop: i64) -> Vec<ScoredMember> {
+        match normalize_range(self.ordered.len(), start, stop) {
+            Some((start, stop)) => self.ordered.iter()
+                .skip(start)
+                .take(stop - start + 1)
+                .map(to_scored_member)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // members with scores between `min` and `max` inclusive
+    pub fn range_by_score(&self, min: f64, max: f64) -> Vec<ScoredMember> {
+        if min > max {
+            return Vec::new();
+        }
+        self.ordered.range((Score(min), Value::new())..)
+            .take_while(|(score, _)| score.0 <= max)
+            .map(to_scored_member)
+            .collect()
+    }
+}
+
+impl Serialize for SortedSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.scores.serialize(serializer)
+    }
+}
+
+impl From<HashMap<Value, f64>> for SortedSet {
+    fn from(scores: HashMap<Value, f64>) -> SortedSet {
+        let mut sorted_set = SortedSet::default();
+        for (member, score) in scores {
+            // can't be added by any request
+            if !score.is_nan() {
+                sorted_set.add(member, score);
+            }
+        }
+        sorted_set
+    }
+}
+
+fn to_scored_member((score, member): &(Score, Value)) -> ScoredMember {
+    ScoredMember { member: member.clone(), score: score.0 }
+}
+
+// converts inclusive range where negative indexes count from the end, like in Redis,
+// into positions within the collection, or none if the range is empty
+fn normalize_range(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { (start + len).max(0) } else { start };
+    let stop = if stop < 0 { stop + len } else { stop.min(len - 1) };
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_range_counts_negative_indexes_from_the_end() {
+        assert_eq!(normalize_range(5, 0, -1), Some((0, 4)));
+        assert_eq!(normalize_range(5, -2, -1), Some((3, 4)));
+        assert_eq!(normalize_range(5, 1, 2), Some((1, 2)));
+    }
+
+    #[test]
+    fn normalize_range_clamps_to_the_collection() {
+        assert_eq!(normalize_range(5, -10, 2), Some((0, 2)));
+        assert_eq!(normalize_range(5, 3, 100), Some((3, 4)));
+    }
+
+    #[test]
+    fn normalize_range_is_empty_outside_the_collection() {
+        assert_eq!(normalize_range(5, 5, 10), None);
+        assert_eq!(normalize_range(5, 3, 1), None);
+        assert_eq!(normalize_range(5, 0, -6), None);
+        assert_eq!(normalize_range(0, 0, -1), None);
+    }
+
+    #[test]
+    fn list_range_and_trim_use_the_same_indexes() {
+        let mut list = List::default();
+        for value in ["a", "b", "c", "d"] {
+            list.push_back(Value::from(value));
+        }
+        assert_eq!(list.range(-3, -2), [Value::from("b"), Value::from("c")]);
+        list.trim(1, -2);
+        assert_eq!(list.range(0, -1), [Value::from("b"), Value::from("c")]);
+        assert_eq!(list.bytes, 2);
+    }
+
+    fn members(scored: Vec<ScoredMember>) -> Vec<(Value, f64)> {
+        scored.into_iter().map(|scored| (scored.member, scored.score)).collect()
+    }
+
+    #[test]
+    fn sorted_set_orders_by_score_then_member() {
+        let mut sorted_set = SortedSet::default();
+        assert!(sorted_set.add(Value::from("b"), 1.0));
+        assert!(sorted_set.add(Value::from("a"), 1.0));
+        assert!(sorted_set.add(Value::from("c"), 0.5));
+        assert!(!sorted_set.add(Value::from("c"), 2.0));
+        assert_eq!(members(sorted_set.range(0, -1)), [(Value::from("a"), 1.0), (Value::from("b"), 1.0), (Value::from("c"), 2.0)]);
+        assert_eq!(sorted_set.rank(&Value::from("b")), Some(1));
+        assert_eq!(members(sorted_set.range_by_score(1.0, 1.5)), [(Value::from("a"), 1.0), (Value::from("b"), 1.0)]);
+        assert_eq!(sorted_set.bytes, 3 * (1 + size_of::<f64>()));
+    }
+
+    #[test]
+    fn sorted_set_keeps_negative_zero_score_as_zero() {
+        let mut sorted_set = SortedSet::default();
+        sorted_set.add(Value::from("a"), -0.0);
+        sorted_set.add(Value::from("b"), 0.0);
+        assert_eq!(members(sorted_set.range_by_score(0.0, 0.0)), [(Value::from("a"), 0.0), (Value::from("b"), 0.0)]);
+        assert!(sorted_set.range_by_score(0.0, 1.0)[0].score.is_sign_positive());
+        assert_eq!(sorted_set.rank(&Value::from("b")), Some(1));
+    }
+
+    #[test]
+    fn sorted_set_incr_by_rejects_nan_score() {
+        let mut sorted_set = SortedSet::default();
+        assert_eq!(sorted_set.incr_by(Value::from("a"), f64::INFINITY).ok(), Some(f64::INFINITY));
+        assert!(sorted_set.incr_by(Value::from("a"), f64::NEG_INFINITY).is_err());
+        assert!(sorted_set.incr_by(Value::from("b"), f64::NAN).is_err());
+        assert_eq!(members(sorted_set.range(0, -1)), [(Value::from("a"), f64::INFINITY)]);
+    }
+
+    #[test]
+    fn sorted_set_is_rebuilt_from_scores_when_received() {
+        let mut sorted_set = SortedSet::default();
+        sorted_set.add(Value::from("b"), 2.0);
+        sorted_set.add(Value::from("a"), 3.0);
+        let bytes = rmp_serde::to_vec_named(&sorted_set).unwrap();
+        let received: SortedSet = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(members(received.range(0, -1)), [(Value::from("b"), 2.0), (Value::from("a"), 3.0)]);
+        assert_eq!(received.bytes, sorted_set.bytes);
+    }
+}
@@ -0,0 +1,230 @@
+use std::io;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::server::framing;
+
+/// Encoding of messages on a connection, detected from the first byte the other side sends.
+#[derive(Debug, Clone, Copy)]
+pub enum WireFormat {
+    // one JSON message per line
+    Json,
+    // MessagePack messages one after another, values are carried as raw bytes
+    MessagePack,
+    // length-prefixed MessagePack frames, after a handshake agreeing on their version
+    Framed,
+    // RESP2 or RESP3 as spoken by Redis clients, replies are written by `resp` module
+    Resp,
+}
+
+impl WireFormat {
+    // JSON request starts with an object or a string, MessagePack one with a map or a string header,
+    // Redis clients send commands as arrays, and frames start with a handshake.
+    // Returns none if connection is closed before anything is sent
+    pub async fn detect<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<WireFormat>> {
+        match reader.fill_buf().await?.first() {
+            None => Ok(None),
+            Some(b'{' | b'"' | b' ' | b'\t' | b'\r' | b'\n') => Ok(Some(WireFormat::Json)),
+            Some(b'*') => Ok(Some(WireFormat::Resp)),
+            Some(&first) if first == framing::FRAME_MAGIC[0] => Ok(Some(WireFormat::Framed)),
+            Some(_) => Ok(Some(WireFormat::MessagePack)),
+        }
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin, T: Serialize>(&self, writer: &mut W, message: &T) -> io::Result<()> {
+        let message = match self {
+            WireFormat::Json => {
+                let mut message_str = serde_json::to_string(message)?;
+                message_str.push('\n');
+                message_str.into_bytes()
+            }
+            // with field names, so clients don't depend on the order of fields
+            WireFormat::MessagePack => rmp_serde::to_vec_named(message).map_err(io::Error::other)?,
+            WireFormat::Framed => framing::encode_frame(message)?,
+            WireFormat::Resp => {
+                unreachable!("RESP reply depends on the command, it can't be written from the response alone");
+            }
+        };
+        writer.write_all(&message).await?;
+        writer.flush().await
+    }
+}
+
+// MessagePack messages have no boundaries, so a message is copied value by value, following sizes in their headers,
+// until all its nested values are received. It is decoded only then, and can't be longer than a frame.
+// Returns none if connection is closed between messages
+pub async fn read_message_pack<R: AsyncBufRead + Unpin, T: DeserializeOwned>(reader: &mut R) -> io::Result<Option<T>> {
+    if reader.fill_buf().await?.is_empty() {
+        return Ok(None);
+    }
+    let mut message = Vec::new();
+    // values still to be read, nested ones are added as headers of their arrays and maps are read
+    let mut pending: usize = 1;
+    while pending > 0 {
+        let marker = read_bytes(reader, &mut message, 1).await?[0];
+        pending -= 1;
+        match value_layout(marker)? {
+            ValueLayout::Fixed(len) => {
+                read_bytes(reader, &mut message, len).await?;
+            }
+            ValueLayout::Items(items) => pending += items,
+            ValueLayout::SizedBytes { size_len, extra } => {
+                let len = read_size(reader, &mut message, size_len).await?;
+                read_bytes(reader, &mut message, len + extra).await?;
+            }
+            ValueLayout::SizedItems { size_len, values_per_item } => {
+                pending += read_size(reader, &mut message, size_len).await? * values_per_item;
+            }
+        }
+        // every value takes at least a byte
+        if pending > framing::MAX_FRAME_LEN - message.len() {
+            return Err(message_too_long());
+        }
+    }
+    rmp_serde::from_slice(&message).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// what follows the marker of a MessagePack value
+enum ValueLayout {
+    // payload of a known length
+    Fixed(usize),
+    // array or map with a known number of values
+    Items(usize),
+    // payload length is in the next `size_len` bytes, ext values have a type byte besides
+    SizedBytes { size_len: usize, extra: usize },
+    // number of items is in the next `size_len` bytes, map items are pairs of values
+    SizedItems { size_len: usize, values_per_item: usize },
+}
+
+fn value_layout(marker: u8) -> io::Result<ValueLayout> {
+    let layout = match marker {
+        0x00..=0x7f | 0xe0..=0xff | 0xc0 | 0xc2 | 0xc3 => ValueLayout::Fixed(0),
+        0x80..=0x8f => ValueLayout::Items(2 * (marker & 0x0f) as usize),
+        0x90..=0x9f => ValueLayout::Items((marker & 0x0f) as usize),
+        0xa0..=0xbf => ValueLayout::Fixed((marker & 0x1f) as usize),
+        0xc1 => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid MessagePack marker 0xc1")),
+        // bin
+        0xc4 => ValueLayout::SizedBytes { size_len: 1, extra: 0 },
+        0xc5 => ValueLayout::SizedBytes { size_len: 2, extra: 0 },
+        0xc6 => ValueLayout::SizedBytes { size_len: 4, extra: 0 },
+        // ext
+        0xc7 => ValueLayout::SizedBytes { size_len: 1, extra: 1 },
+        0xc8 => ValueLayout::SizedBytes { size_len: 2, extra: 1 },
+        0xc9 => ValueLayout::SizedBytes { size_len: 4, extra: 1 },
+        // floats, unsigned and signed integers
+        0xca => ValueLayout::Fixed(4),
+        0xcb => ValueLayout::Fixed(8),
+        0xcc | 0xd0 => ValueLayout::Fixed(1),
+        0xcd | 0xd1 => ValueLayout::Fixed(2),
+        0xce | 0xd2 => ValueLayout::Fixed(4),
+        0xcf | 0xd3 => ValueLayout::Fixed(8),
+        // fixext, with the type byte
+        0xd4 => ValueLayout::Fixed(2),
+        0xd5 => ValueLayout::Fixed(3),
+        0xd6 => ValueLayout::Fixed(5),
+        0xd7 => ValueLayout::Fixed(9),
+        0xd8 => ValueLayout::Fixed(17),
+        // str
+        0xd9 => ValueLayout::SizedBytes { size_len: 1, extra: 0 },
+        0xda => ValueLayout::SizedBytes { size_len: 2, extra: 0 },
+        0xdb => ValueLayout::SizedBytes { size_len: 4, extra: 0 },
+        // array and map
+        0xdc => ValueLayout::SizedItems { size_len: 2, values_per_item: 1 },
+        0xdd => ValueLayout::SizedItems { size_len: 4, values_per_item: 1 },
+        0xde => ValueLayout::SizedItems { size_len: 2, values_per_item: 2 },
+        0xdf => ValueLayout::SizedItems { size_len: 4, values_per_item: 2 },
+    };
+    Ok(layout)
+}
+
+// reads a big-endian size of `size_len` bytes
+async fn read_size<R: AsyncBufRead + Unpin>(reader: &mut R, message: &mut Vec<u8>, size_len: usize) -> io::Result<usize> {
+    let size = read_bytes(reader, message, size_len).await?;
+    Ok(size.iter().fold(0, |size, &byte| (size << 8) | byte as usize))
+}
+
+// appends next `len` bytes to the message, returns them
+async fn read_bytes<'a, R: AsyncBufRead + Unpin>(reader: &mut R, message: &'a mut Vec<u8>, len: usize) -> io::Result<&'a [u8]> {
+    if len > framing::MAX_FRAME_LEN - message.len() {
+        return Err(message_too_long());
+    }
+    // message grows as bytes arrive, so a long announced size doesn't allocate before the data is sent
+    let start = message.len();
+    if reader.take(len as u64).read_to_end(message).await? < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(&message[start..])
+}
+
+fn message_too_long() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("MessagePack message is longer than {} bytes", framing::MAX_FRAME_LEN))
+}
+
+#[cfg(test)]
+mod tests {
+    use rmpv::Value;
+    use serde::de::IgnoredAny;
+    use crate::server::test_util::block_on;
+    use super::*;
+
+    fn encode(value: &Value) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        rmpv::encode::write_value(&mut bytes, value).unwrap();
+        bytes
+    }
+
+    fn read_first<T: DeserializeOwned>(mut bytes: &[u8]) -> io::Result<Option<T>> {
+        block_on(read_message_pack(&mut bytes))
+    }
+
+    #[test]
+    fn reads_nested_message() {
+        let message = Value::Map(vec![
+            (Value::from("list"), Value::Array((0..20).map(Value::from).collect())),
+            (Value::from("text"), Value::from("x".repeat(300))),
+            (Value::from("numbers"), Value::Array(vec![Value::from(-5), Value::from(u64::MAX), Value::from(1.5)])),
+            (Value::from("nil"), Value::Nil),
+        ]);
+        let expected = serde_json::json!({
+            "list": (0..20).collect::<Vec<_>>(),
+            "text": "x".repeat(300),
+            "numbers": [-5, u64::MAX, 1.5],
+            "nil": null,
+        });
+        assert_eq!(read_first::<serde_json::Value>(&encode(&message)).unwrap(), Some(expected));
+    }
+
+    #[test]
+    fn stops_at_the_end_of_each_message() {
+        let first = Value::Array(vec![Value::Binary(vec![1, 2, 3]), Value::Ext(7, vec![0; 4]), Value::Ext(1, vec![0; 20])]);
+        let bytes = [encode(&first), encode(&Value::from("second"))].concat();
+        let mut reader = bytes.as_slice();
+        block_on(async {
+            let _: IgnoredAny = read_message_pack(&mut reader).await.unwrap().unwrap();
+            let second: String = read_message_pack(&mut reader).await.unwrap().unwrap();
+            assert_eq!(second, "second");
+            assert!(read_message_pack::<_, IgnoredAny>(&mut reader).await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn truncated_message_is_an_error() {
+        let bytes = encode(&Value::Array(vec![Value::from("a"), Value::from("b")]));
+        let error = read_first::<IgnoredAny>(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn announced_sizes_over_the_limit_are_rejected_before_reading() {
+        // str32 and array32 with the largest lengths, nothing follows their headers
+        for header in [[0xdb, 0xff, 0xff, 0xff, 0xff], [0xdd, 0xff, 0xff, 0xff, 0xff]] {
+            let error = read_first::<IgnoredAny>(&header).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn unused_marker_is_rejected() {
+        assert_eq!(read_first::<IgnoredAny>(&[0xc1]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}